@@ -0,0 +1,109 @@
+use futures_util::future::join_all;
+use std::sync::Arc;
+
+use crate::{
+    ml::{engine::MLEngine, model::Payload},
+    models::{
+        BatchInferenceRequest, BatchInferenceResponse, BatchItemResult, ErrorMessage, MessageType,
+        resolve_model, ModelError, ModelType,
+    },
+};
+
+use super::error_code;
+
+const MAX_BATCH_ITEMS: usize = 256;
+
+// Shared by the REST and WebSocket batch endpoints. Only a malformed batch fails as a
+// whole, item failures are reported in that item's result.
+pub async fn run_batch(engine: &Arc<MLEngine>, request: BatchInferenceRequest) -> anyhow::Result<BatchInferenceResponse> {
+    if request.items.is_empty() || request.items.len() > MAX_BATCH_ITEMS {
+        return Err(ModelError::InvalidInput(format!(
+            "batch must have between 1 and {} items, got {}",
+            MAX_BATCH_ITEMS,
+            request.items.len()
+        )).into());
+    }
+
+    let start = std::time::Instant::now();
+    let tasks: Vec<_> = request.items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let engine = engine.clone();
+            // Whatever the item names wins over the batch defaults
+            let model = resolve_model(item.model.as_deref(), item.model_type)
+                .or_else(|_| resolve_model(request.model.as_deref(), request.model_type))
+                .map(str::to_string)
+                .ok();
+            let seed = item.seed.or(request.seed);
+            let client = request.client_id.clone();
+            let request_id = item.request_id.clone();
+            let item_model = model.clone();
+            // On the blocking pool, so CPU-heavy items neither stall the runtime nor each other
+            let task = tokio::task::spawn_blocking(move || {
+                run_item(&engine, item_model.as_deref(), item.data, seed, client.as_deref())
+            });
+            (index, model, request_id, task)
+        })
+        .collect();
+
+    let results: Vec<BatchItemResult> = join_all(tasks.into_iter().map(|(index, model, request_id, task)| async move {
+        let (outcome, latency_ms) = match task.await {
+            Ok(done) => done,
+            Err(e) => (Err(anyhow::anyhow!("inference task failed: {}", e)), 0.0),
+        };
+        let (model_type, version, prediction, error) = match outcome {
+            Ok((model_type, version, prediction)) => (Some(model_type), Some(version), Some(prediction), None),
+            Err(e) => {
+                let model_type = model.as_deref().and_then(|model| engine.model_kind(model).ok());
+                let error = ErrorMessage {
+                    code: error_code(&e, MessageType::InferenceRequest),
+                    message: e.to_string(),
+                    message_type: Some(MessageType::InferenceRequest),
+                    model_type,
+                    request_id: request_id.clone(),
+                };
+                (model_type, None, None, Some(error))
+            }
+        };
+        BatchItemResult {
+            index,
+            model_type,
+            model,
+            version,
+            request_id,
+            prediction,
+            error,
+            latency_ms,
+        }
+    }))
+    .await;
+
+    let failed = results.iter().filter(|result| result.error.is_some()).count();
+    Ok(BatchInferenceResponse {
+        succeeded: results.len() - failed,
+        failed,
+        results,
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        timestamp: chrono::Utc::now(),
+        request_id: request.request_id,
+    })
+}
+
+fn run_item(
+    engine: &MLEngine,
+    model: Option<&str>,
+    data: serde_json::Value,
+    seed: Option<u64>,
+    client: Option<&str>,
+) -> (anyhow::Result<(ModelType, String, serde_json::Value)>, f64) {
+    let start = std::time::Instant::now();
+    let result = match model {
+        Some(model) => engine
+            .infer(model, Payload::Json(data), seed, client)
+            .and_then(|inference| Ok((inference.model_type, inference.version, inference.output.to_json()?))),
+        None => Err(ModelError::InvalidInput("item names no model and the batch sets none".to_string()).into()),
+    };
+    (result, start.elapsed().as_secs_f64() * 1000.0)
+}
+
@@ -1,2 +1,22 @@
 pub mod websocket;
-pub mod rest;
\ No newline at end of file
+pub mod rest;
+pub mod batch;
+
+use crate::models::{ErrorCode, MessageType, ModelError};
+
+// The code clients see for a failed request, shared by the websocket and batch handlers
+pub(crate) fn error_code(e: &anyhow::Error, message_type: MessageType) -> ErrorCode {
+    match e.downcast_ref::<ModelError>() {
+        Some(ModelError::InvalidInput(_)) => return ErrorCode::InvalidInput,
+        Some(ModelError::UnknownModel(_)) => return ErrorCode::UnknownModel,
+        Some(ModelError::Unavailable(_)) => return ErrorCode::ModelUnavailable,
+        None => {}
+    }
+    if e.downcast_ref::<serde_json::Error>().is_some() {
+        return ErrorCode::InvalidInput;
+    }
+    match message_type {
+        MessageType::InferenceRequest | MessageType::BatchInferenceRequest => ErrorCode::InferenceFailed,
+        _ => ErrorCode::Internal,
+    }
+}
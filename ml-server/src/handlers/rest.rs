@@ -1,92 +1,470 @@
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
     Json,
 };
+use serde::Deserialize;
 use std::sync::Arc;
 
 use crate::{
+    ml::{
+        experiment::{ExperimentConfig, TrafficSplit},
+        model::Payload,
+        pipeline::PipelineRequest,
+        registry::ModelConfig,
+        shadow::ShadowConfig,
+    },
     models::{
-        trajectory::TrajectoryPredictionInput,
-        anomaly::AnomalyDetectionInput,
-        objects::ObjectDetectionInput,
-        fusion::FusionInput,
-        InferenceRequest, InferenceResponse, ModelType,
+        binary::WireEncoding,
+        frame::EncodedImage,
+        isolation_forest::ForestTrainingInput,
+        objects::{ObjectDetectionInput, PostProcessing},
+        BatchInferenceRequest, InferenceResponse, ModelError, ModelType, ThresholdUpdate,
     },
     state::AppState,
 };
 
-pub async fn list_models() -> impl IntoResponse {
-    Json(vec![
-        ModelType::TrajectoryPrediction,
-        ModelType::AnomalyDetection,
-        ModelType::ObjectDetection,
-        ModelType::SensorFusion,
-    ])
+use super::batch::run_batch;
+
+pub async fn list_models(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.ml_engine.models())
+}
+
+pub async fn register_model(
+    State(state): State<Arc<AppState>>,
+    Json(config): Json<ModelConfig>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let info = state.ml_engine.register_model(config).map_err(engine_error)?;
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn unregister_model(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    state.ml_engine.unregister_model(&model_name(&name)).map_err(engine_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn reload_model(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let info = state.ml_engine.reload_model(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(info))
+}
+
+pub async fn rollback_model(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let info = state.ml_engine.rollback_model(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(info))
+}
+
+pub async fn start_experiment(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(config): Json<ExperimentConfig>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let info = state.ml_engine.start_experiment(&model_name(&name), config).map_err(engine_error)?;
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+pub async fn update_experiment(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(split): Json<TrafficSplit>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let experiment = state.ml_engine.update_experiment(&model_name(&name), split).map_err(engine_error)?;
+    Ok(Json(experiment))
+}
+
+pub async fn stop_experiment(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let experiment = state.ml_engine.stop_experiment(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(experiment))
+}
+
+pub async fn promote_candidate(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let info = state.ml_engine.promote_candidate(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(info))
+}
+
+pub async fn start_shadow(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(config): Json<ShadowConfig>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let report = state.ml_engine.start_shadow(&model_name(&name), config).map_err(engine_error)?;
+    Ok((StatusCode::CREATED, Json(report)))
+}
+
+pub async fn shadow_report(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let report = state.ml_engine.shadow_report(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(report))
+}
+
+pub async fn stop_shadow(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let report = state.ml_engine.stop_shadow(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(report))
+}
+
+pub async fn model_stats(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let stats = state.ml_engine.model_stats(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(stats))
+}
+
+pub async fn model_classes(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let classes = state.ml_engine.model_classes(&model_name(&name)).map_err(engine_error)?;
+    Ok(Json(classes))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SaveRequest {
+    // Label for the saved artifact, defaults to the active version
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+pub async fn save_model(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    body: axum::body::Bytes,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    // The body is optional, but a malformed one is still rejected
+    let request: SaveRequest = if body.is_empty() {
+        SaveRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    };
+    let saved = state.ml_engine.save_model(&model_name(&name), request.version).map_err(engine_error)?;
+    Ok((StatusCode::CREATED, Json(saved)))
+}
+
+pub async fn model_versions(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let name = model_name(&name);
+    state.model_versions
+        .read()
+        .unwrap()
+        .get(&name)
+        .cloned()
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, format!("Unknown model: {}", name)))
+}
+
+// Anomaly management works on any registered anomaly model, `anomaly` is the default one
+pub async fn anomaly_baselines(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let baselines = state.ml_engine.anomaly_baselines(&model_name(&name)).await.map_err(model_error)?;
+    Ok(Json(baselines))
+}
+
+pub async fn reset_anomaly_baselines(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let reset = state.ml_engine.reset_anomaly_baselines(&model_name(&name), None).await.map_err(model_error)?;
+    Ok(Json(serde_json::json!({ "reset": reset })))
+}
+
+pub async fn reset_anomaly_baseline(
+    Path((name, sensor_type)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    match state.ml_engine.reset_anomaly_baselines(&model_name(&name), Some(&sensor_type)).await.map_err(model_error)? {
+        0 => Err((
+            StatusCode::NOT_FOUND,
+            format!("No baseline for sensor: {}", sensor_type),
+        )),
+        reset => Ok(Json(serde_json::json!({ "reset": reset }))),
+    }
+}
+
+pub async fn train_anomaly_forest(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(input): Json<ForestTrainingInput>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let summary = state.ml_engine.train_anomaly_forest(&model_name(&name), input).await
+        .map_err(engine_error)?;
+    Ok(Json(summary))
+}
+
+pub async fn anomaly_forest(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    state.ml_engine.anomaly_forest(&model_name(&name)).await
+        .map_err(model_error)?
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "No isolation forest trained".to_string()))
+}
+
+pub async fn clear_anomaly_forest(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if state.ml_engine.clear_anomaly_forest(&model_name(&name)).await.map_err(model_error)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "No isolation forest trained".to_string()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InferenceParams {
+    pub seed: Option<u64>,
+}
+
+pub async fn anomaly_threshold(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let config = state.ml_engine.anomaly_thresholds(&model_name(&name)).await.map_err(model_error)?;
+    Ok(Json(config))
+}
+
+pub async fn update_anomaly_threshold(
+    Path(name): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<ThresholdUpdate>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let config = state.ml_engine
+        .update_anomaly_threshold(&model_name(&name), update.sensor_type.as_deref(), update.threshold)
+        .await
+        .map_err(engine_error)?;
+    Ok(Json(config))
+}
+
+pub async fn clear_anomaly_sensor_threshold(
+    Path((name, sensor_type)): Path<(String, String)>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let cleared = state.ml_engine
+        .clear_anomaly_sensor_threshold(&model_name(&name), &sensor_type)
+        .await
+        .map_err(engine_error)?;
+    match cleared {
+        Some(config) => Ok(Json(config)),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            format!("No threshold override for sensor: {}", sensor_type),
+        )),
+    }
+}
+
+// Keeps a sticky experiment on one version across requests
+const CLIENT_ID_HEADER: &str = "x-client-id";
+
+fn client_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get(CLIENT_ID_HEADER).and_then(|value| value.to_str().ok())
 }
 
 pub async fn inference(
     Path(model): Path<String>,
+    Query(params): Query<InferenceParams>,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(request): Json<serde_json::Value>,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let model_type = match model.as_str() {
-        "trajectory" => ModelType::TrajectoryPrediction,
-        "anomaly" => ModelType::AnomalyDetection,
-        "objects" => ModelType::ObjectDetection,
-        "fusion" => ModelType::SensorFusion,
-        _ => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                format!("Unknown model: {}", model),
-            ))
-        }
+    run_inference(&state, model_name(&model), Payload::Json(request), params.seed, client_id(&headers)).await
+}
+
+// Room for a full resolution frame, base64 JSON included
+pub const MAX_UPLOAD_BYTES: usize = 32 * 1024 * 1024;
+
+// Multipart form with the frame as an `image` file part, JPEG or PNG, and optional
+// frame_id, stream_id, timestamp and simulate_complex text parts, plus postprocess as JSON
+pub async fn upload_inference(
+    Path(model): Path<String>,
+    Query(params): Query<InferenceParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let model = model_name(&model);
+    let kind = state.ml_engine.model_kind(&model).map_err(|e| engine_error(e.into()))?;
+    if kind != ModelType::ObjectDetection {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("model {} does not take images, uploads are for object detection", model),
+        ));
+    }
+
+    let bad_request = |message: String| (StatusCode::BAD_REQUEST, message);
+    let mut input = ObjectDetectionInput {
+        frame_id: String::new(),
+        timestamp: chrono::Utc::now().timestamp_millis(),
+        stream_id: None,
+        image: None,
+        encoded_image: None,
+        simulate_complex: false,
+        postprocess: PostProcessing::default(),
     };
-    
+    while let Some(field) = multipart.next_field().await.map_err(|e| bad_request(e.body_text()))? {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "image" => {
+                if input.frame_id.is_empty() {
+                    input.frame_id = field.file_name().unwrap_or_default().to_string();
+                }
+                let bytes = field.bytes().await.map_err(|e| bad_request(e.body_text()))?;
+                input.encoded_image = Some(EncodedImage(bytes.to_vec()));
+            }
+            "frame_id" => {
+                input.frame_id = field.text().await.map_err(|e| bad_request(e.body_text()))?;
+            }
+            "stream_id" => {
+                input.stream_id = Some(field.text().await.map_err(|e| bad_request(e.body_text()))?);
+            }
+            "timestamp" => {
+                let text = field.text().await.map_err(|e| bad_request(e.body_text()))?;
+                input.timestamp = text.trim().parse()
+                    .map_err(|e| bad_request(format!("invalid timestamp: {}", e)))?;
+            }
+            "simulate_complex" => {
+                let text = field.text().await.map_err(|e| bad_request(e.body_text()))?;
+                input.simulate_complex = text.trim().parse()
+                    .map_err(|e| bad_request(format!("invalid simulate_complex: {}", e)))?;
+            }
+            "postprocess" => {
+                let text = field.text().await.map_err(|e| bad_request(e.body_text()))?;
+                input.postprocess = serde_json::from_str(&text)
+                    .map_err(|e| bad_request(format!("invalid postprocess: {}", e)))?;
+            }
+            _ => return Err(bad_request(format!("unexpected form field {:?}", name))),
+        }
+    }
+    if input.encoded_image.is_none() {
+        return Err(bad_request("the form has no image file".to_string()));
+    }
+    if input.frame_id.is_empty() {
+        input.frame_id = "upload".to_string();
+    }
+
+    // MessagePack carries the file as bytes, JSON would need base64
+    let encoding = WireEncoding::MessagePack;
+    let payload = Payload::Encoded(
+        encoding,
+        encoding.encode(&input).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+    );
+    run_inference(&state, model, payload, params.seed, client_id(&headers)).await
+}
+
+async fn run_inference(
+    state: &AppState,
+    model: String,
+    input: Payload,
+    seed: Option<u64>,
+    client: Option<&str>,
+) -> Result<Json<InferenceResponse>, (StatusCode, String)> {
     let start = std::time::Instant::now();
     
-    let prediction = match model_type {
-        ModelType::TrajectoryPrediction => {
-            let input: TrajectoryPredictionInput = serde_json::from_value(request)
-                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
-            let output = state.ml_engine.predict_trajectory(input).await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            serde_json::to_value(output)
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        }
-        ModelType::AnomalyDetection => {
-            let input: AnomalyDetectionInput = serde_json::from_value(request)
-                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
-            let output = state.ml_engine.detect_anomaly(input).await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            serde_json::to_value(output)
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        }
-        ModelType::ObjectDetection => {
-            let input: ObjectDetectionInput = serde_json::from_value(request)
-                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
-            let output = state.ml_engine.detect_objects(input).await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            serde_json::to_value(output)
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        }
-        ModelType::SensorFusion => {
-            let input: FusionInput = serde_json::from_value(request)
-                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
-            let output = state.ml_engine.fuse_sensors(input).await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
-            serde_json::to_value(output)
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        }
-    };
+    let inference = state.ml_engine
+        .infer_blocking(model.clone(), input, seed, client.map(str::to_string))
+        .await
+        .map_err(engine_error)?;
+    let prediction = inference.output.to_json()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     
     let response = InferenceResponse {
-        model_type,
+        model_type: inference.model_type,
+        model,
+        version: inference.version,
         prediction,
         latency_ms: start.elapsed().as_secs_f64() * 1000.0,
         timestamp: chrono::Utc::now(),
+        request_id: None,
     };
     
     Ok(Json(response))
-}
\ No newline at end of file
+}
+
+// The path names the detector, the body picks the trajectory model
+pub async fn pipeline(
+    Path(model): Path<String>,
+    Query(params): Query<InferenceParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(request): Json<PipelineRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let engine = state.ml_engine.clone();
+    let client = client_id(&headers).map(str::to_string);
+    let response = tokio::task::spawn_blocking(move || {
+        engine.pipeline(&model_name(&model), request, params.seed, client.as_deref())
+    })
+    .await
+    .map_err(|e| engine_error(e.into()))?
+    .map_err(engine_error)?;
+    Ok(Json(response))
+}
+
+// The path model is the default for items that do not name one, a query seed for items without a seed
+pub async fn batch_inference(
+    Path(model): Path<String>,
+    Query(params): Query<InferenceParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(mut request): Json<BatchInferenceRequest>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    request.model = Some(model_name(&model));
+    request.seed = request.seed.or(params.seed);
+    if request.client_id.is_none() {
+        request.client_id = client_id(&headers).map(str::to_string);
+    }
+    let response = run_batch(&state.ml_engine, request).await
+        .map_err(engine_error)?;
+    Ok(Json(response))
+}
+
+// Short aliases for the default instances, anything else is a registered model name.
+// Inference and every /api/models/:name route accept them.
+fn model_name(model: &str) -> String {
+    let model_type = match model {
+        "trajectory" => ModelType::TrajectoryPrediction,
+        "anomaly" => ModelType::AnomalyDetection,
+        "objects" => ModelType::ObjectDetection,
+        "fusion" => ModelType::SensorFusion,
+        _ => return model.to_string(),
+    };
+    model_type.default_name().to_string()
+}
+
+fn model_error(e: ModelError) -> (StatusCode, String) {
+    engine_error(e.into())
+}
+
+fn engine_error(e: anyhow::Error) -> (StatusCode, String) {
+    let status = match e.downcast_ref::<ModelError>() {
+        Some(ModelError::InvalidInput(_)) => StatusCode::BAD_REQUEST,
+        Some(ModelError::UnknownModel(_)) => StatusCode::NOT_FOUND,
+        Some(ModelError::Unavailable(_)) => StatusCode::SERVICE_UNAVAILABLE,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
@@ -1,126 +1,509 @@
 use axum::extract::ws::{Message, WebSocket};
 use futures_util::{sink::SinkExt, stream::StreamExt};
-use std::sync::Arc;
-use tracing::{debug, error, info};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+use tokio::sync::{broadcast::error::RecvError, mpsc, Semaphore};
+use tokio::task::AbortHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, warn};
 
 use crate::{
+    ml::model::Payload,
     models::{
-        trajectory::TrajectoryPredictionInput,
-        anomaly::{AnomalyDetectionInput, SensorData},
-        objects::ObjectDetectionInput,
-        fusion::FusionInput,
-        InferenceRequest, InferenceResponse, MessageType, ModelType, WebSocketMessage,
+        binary::{BinaryRequest, BinaryResponse, WireEncoding},
+        AnomalyModelRequest, AnomalyThresholdReply, AnomalyThresholdRequest, BatchInferenceRequest, ErrorCode,
+        ErrorMessage, InferenceRequest, InferenceResponse, MessageType, ModelError, resolve_model, ModelType,
+        SubscribeRequest, SubscriptionUpdate, UnsubscribeRequest, WebSocketMessage,
     },
     state::AppState,
 };
 
-pub async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
-    info!("New WebSocket connection established");
+use super::{batch::run_batch, error_code};
+
+// Inference requests running at once on a single connection, further requests wait
+const MAX_IN_FLIGHT_REQUESTS: usize = 16;
+const OUTBOUND_BUFFER: usize = 64;
+const MAX_SUBSCRIPTIONS: usize = 8;
+const MAX_STREAM_RATE_HZ: f32 = 60.0;
+const DEFAULT_STREAM_RATE_HZ: f32 = 10.0;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Cheap handle shared by the reader loop and spawned request tasks. Everything sent
+// goes through one writer task, so responses go out in completion order.
+#[derive(Clone)]
+struct Connection {
+    id: String,
+    state: Arc<AppState>,
+    outbound: mpsc::Sender<Message>,
+    subscriptions: Arc<Mutex<HashMap<String, AbortHandle>>>,
+    // Negotiated through the subprotocol, used for replies to binary frames
+    encoding: WireEncoding,
+}
+
+impl Connection {
+    async fn send(&self, message: Message) -> anyhow::Result<()> {
+        self.outbound
+            .send(message)
+            .await
+            .map_err(|_| anyhow::anyhow!("WebSocket writer has shut down"))
+    }
+
+    async fn send_message<T: Serialize>(&self, message_type: MessageType, payload: &T) -> anyhow::Result<()> {
+        let message = WebSocketMessage {
+            message_type,
+            payload: serde_json::to_value(payload)?,
+        };
+        self.send(Message::Text(serde_json::to_string(&message)?)).await
+    }
+
+    async fn send_error(&self, error: ErrorMessage) -> anyhow::Result<()> {
+        self.send_message(MessageType::Error, &error).await
+    }
+
+    async fn send_binary(&self, response: &BinaryResponse) -> anyhow::Result<()> {
+        self.send(Message::Binary(self.encoding.encode(response)?)).await
+    }
+}
+
+pub async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
+    let encoding = socket
+        .protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(WireEncoding::from_protocol)
+        .unwrap_or_default();
+    info!("New WebSocket connection established, binary frames use {:?}", encoding);
+
+    let (mut sink, mut stream) = socket.split();
+    let (outbound, mut outbound_rx) = mpsc::channel(OUTBOUND_BUFFER);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outbound_rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
 
-    while let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            match msg {
-                Message::Text(text) => {
-                    if let Err(e) = handle_text_message(&mut socket, &state, text).await {
-                        error!("Error handling message: {}", e);
+    let conn = Connection {
+        id: format!("conn-{}", NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)),
+        state: state.clone(),
+        outbound,
+        subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        encoding,
+    };
+    state.active_connections.insert(conn.id.clone(), conn.outbound.clone());
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_REQUESTS));
+    let mut updates = state.ml_engine.subscribe_updates();
+
+    loop {
+        tokio::select! {
+            msg = stream.next() => {
+                let Some(Ok(msg)) = msg else {
+                    break;
+                };
+                let message = match msg {
+                    Message::Text(text) => decode_text_message(&text),
+                    Message::Binary(data) => {
+                        let request = match conn.encoding.decode::<BinaryRequest>(&data) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                let error = ErrorMessage::new(ErrorCode::InvalidMessage, e.to_string());
+                                if conn.send_binary(&BinaryResponse::Error(error)).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                        };
+
+                        match request {
+                            BinaryRequest::Inference { request_id, seed, model, input } => {
+                                let Ok(permit) = in_flight.clone().acquire_owned().await else {
+                                    break;
+                                };
+                                let conn = conn.clone();
+                                tokio::spawn(async move {
+                                    if let Err(e) = handle_binary_inference(&conn, request_id, seed, model, input).await {
+                                        error!("Error handling binary message: {}", e);
+                                    }
+                                    drop(permit);
+                                });
+                            }
+                            BinaryRequest::Heartbeat => {
+                                let response = BinaryResponse::Heartbeat { timestamp: chrono::Utc::now() };
+                                if conn.send_binary(&response).await.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        continue;
                     }
-                }
-                Message::Binary(data) => {
-                    if let Err(e) = handle_binary_message(&mut socket, &state, data).await {
-                        error!("Error handling binary message: {}", e);
+                    Message::Ping(data) => {
+                        if conn.send(Message::Pong(data)).await.is_err() {
+                            break;
+                        }
+                        continue;
                     }
-                }
-                Message::Ping(data) => {
-                    if socket.send(Message::Pong(data)).await.is_err() {
+                    Message::Close(_) => {
+                        info!("WebSocket connection closed");
                         break;
                     }
-                }
-                Message::Close(_) => {
-                    info!("WebSocket connection closed");
+                    _ => continue,
+                };
+
+                let message = match message {
+                    Ok(message) => message,
+                    Err(error) => {
+                        if conn.send_error(error).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                // Inference runs off the reader so a slow model does not hold up the connection
+                if matches!(message.message_type, MessageType::InferenceRequest | MessageType::BatchInferenceRequest) {
+                    let Ok(permit) = in_flight.clone().acquire_owned().await else {
+                        break;
+                    };
+                    let conn = conn.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_message(&conn, message).await {
+                            error!("Error handling message: {}", e);
+                        }
+                        drop(permit);
+                    });
+                } else if let Err(e) = handle_message(&conn, message).await {
+                    error!("Error handling message: {}", e);
                     break;
                 }
-                _ => {}
             }
-        } else {
-            break;
+            update = updates.recv() => {
+                match update {
+                    Ok(update) => {
+                        if conn.send_message(MessageType::ModelUpdate, &update).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("WebSocket client missed {} model updates", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
         }
     }
+
+    state.active_connections.remove(&conn.id);
+    for (_, stream) in conn.subscriptions.lock().unwrap().drain() {
+        stream.abort();
+    }
+    debug!("{} closed, {} connections remain", conn.id, state.active_connections.len());
+
+    // In-flight tasks hold their own senders, the writer finishes once they are done
+    drop(conn);
+    let _ = writer.await;
+}
+
+fn decode_text_message(text: &str) -> Result<WebSocketMessage, ErrorMessage> {
+    serde_json::from_str(text).map_err(|e| ErrorMessage::new(ErrorCode::InvalidMessage, e.to_string()))
 }
 
-async fn handle_text_message(
-    socket: &mut WebSocket,
-    state: &Arc<AppState>,
-    text: String,
+// Binary replies use the connection's encoding, failures included
+async fn handle_binary_inference(
+    conn: &Connection,
+    request_id: Option<String>,
+    seed: Option<u64>,
+    model: String,
+    input: Vec<u8>,
 ) -> anyhow::Result<()> {
-    let message: WebSocketMessage = serde_json::from_str(&text)?;
-    
+    let start = std::time::Instant::now();
+    let output = conn.state.ml_engine
+        .infer_blocking(model.clone(), Payload::Encoded(conn.encoding, input), seed, Some(conn.id.clone()))
+        .await
+        .and_then(|inference| Ok((inference.model_type, inference.version, inference.output.encode(conn.encoding)?)));
+    let response = match output {
+        Ok((model_type, version, output)) => BinaryResponse::Inference {
+            request_id,
+            model_type,
+            model,
+            version,
+            output,
+            latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+            timestamp: chrono::Utc::now(),
+        },
+        Err(e) => {
+            debug!("Binary request for {} failed: {}", model, e);
+            BinaryResponse::Error(ErrorMessage {
+                code: error_code(&e, MessageType::InferenceRequest),
+                message: e.to_string(),
+                message_type: Some(MessageType::InferenceRequest),
+                model_type: conn.state.ml_engine.model_kind(&model).ok(),
+                request_id,
+            })
+        }
+    };
+    conn.send_binary(&response).await
+}
+
+// Failures are reported back to the client, only a failed send is returned
+async fn handle_message(conn: &Connection, message: WebSocketMessage) -> anyhow::Result<()> {
+    let message_type = message.message_type;
+    // Pulled out up front so they can be echoed even if the payload fails to parse
+    let model_type = message.payload
+        .get("model_type")
+        .and_then(|value| serde_json::from_value(value.clone()).ok());
+    let request_id = message.payload
+        .get("request_id")
+        .or_else(|| message.payload.get("subscription_id"))
+        .and_then(|value| value.as_str())
+        .map(str::to_string);
+
+    if let Err(e) = dispatch_message(conn, message).await {
+        debug!("Request {:?} failed: {}", message_type, e);
+        let error = ErrorMessage {
+            code: error_code(&e, message_type),
+            message: e.to_string(),
+            message_type: Some(message_type),
+            model_type,
+            request_id,
+        };
+        conn.send_error(error).await?;
+    }
+    Ok(())
+}
+
+async fn dispatch_message(conn: &Connection, message: WebSocketMessage) -> anyhow::Result<()> {
+    let state = &conn.state;
     match message.message_type {
         MessageType::InferenceRequest => {
             let request: InferenceRequest = serde_json::from_value(message.payload)?;
+            let model = resolve_model(request.model.as_deref(), request.model_type)?;
             let start = std::time::Instant::now();
-            
-            let prediction = match request.model_type {
-                ModelType::TrajectoryPrediction => {
-                    let input: TrajectoryPredictionInput = serde_json::from_value(request.data)?;
-                    let output = state.ml_engine.predict_trajectory(input).await?;
-                    serde_json::to_value(output)?
-                }
-                ModelType::AnomalyDetection => {
-                    let input: AnomalyDetectionInput = serde_json::from_value(request.data)?;
-                    let output = state.ml_engine.detect_anomaly(input).await?;
-                    serde_json::to_value(output)?
-                }
-                ModelType::ObjectDetection => {
-                    let input: ObjectDetectionInput = serde_json::from_value(request.data)?;
-                    let output = state.ml_engine.detect_objects(input).await?;
-                    serde_json::to_value(output)?
-                }
-                ModelType::SensorFusion => {
-                    let input: FusionInput = serde_json::from_value(request.data)?;
-                    let output = state.ml_engine.fuse_sensors(input).await?;
-                    serde_json::to_value(output)?
-                }
-            };
+            let client = request.client_id.as_deref().unwrap_or(&conn.id);
+            let (model_type, version, prediction) =
+                run_inference(state, model, request.data, request.seed, Some(client)).await?;
             
             let response = InferenceResponse {
-                model_type: request.model_type,
+                model_type,
+                model: model.to_string(),
+                version,
                 prediction,
                 latency_ms: start.elapsed().as_secs_f64() * 1000.0,
                 timestamp: chrono::Utc::now(),
+                request_id: request.request_id,
             };
             
-            let ws_response = WebSocketMessage {
-                message_type: MessageType::InferenceResponse,
-                payload: serde_json::to_value(response)?,
-            };
-            
-            socket.send(Message::Text(serde_json::to_string(&ws_response)?)).await?;
+            conn.send_message(MessageType::InferenceResponse, &response).await?;
+        }
+        MessageType::BatchInferenceRequest => {
+            let mut request: BatchInferenceRequest = serde_json::from_value(message.payload)?;
+            request.client_id.get_or_insert_with(|| conn.id.clone());
+            let response = run_batch(&state.ml_engine, request).await?;
+            conn.send_message(MessageType::BatchInferenceResponse, &response).await?;
         }
         MessageType::Heartbeat => {
-            let response = WebSocketMessage {
-                message_type: MessageType::Heartbeat,
-                payload: serde_json::json!({ "timestamp": chrono::Utc::now() }),
+            let payload = serde_json::json!({ "timestamp": chrono::Utc::now() });
+            conn.send_message(MessageType::Heartbeat, &payload).await?;
+        }
+        MessageType::GetAnomalyThreshold => {
+            // No payload at all asks for the default model
+            let request: AnomalyModelRequest =
+                serde_json::from_value::<Option<_>>(message.payload)?.unwrap_or_default();
+            let reply = AnomalyThresholdReply {
+                model: request.name().to_string(),
+                config: state.ml_engine.anomaly_thresholds(request.name()).await?,
+            };
+            conn.send_message(MessageType::AnomalyThreshold, &reply).await?;
+        }
+        MessageType::SetAnomalyThreshold => {
+            // The new configuration reaches this client through the model update broadcast
+            let AnomalyThresholdRequest { target, update } = serde_json::from_value(message.payload)?;
+            state.ml_engine
+                .update_anomaly_threshold(target.name(), update.sensor_type.as_deref(), update.threshold)
+                .await?;
+        }
+        MessageType::Subscribe => {
+            let request: SubscribeRequest = serde_json::from_value(message.payload)?;
+            subscribe(conn, request).await?;
+        }
+        MessageType::Unsubscribe => {
+            let request: UnsubscribeRequest = serde_json::from_value(message.payload)?;
+            let stream = conn.subscriptions.lock().unwrap().remove(&request.subscription_id);
+            let Some(stream) = stream else {
+                return Err(ModelError::InvalidInput(format!(
+                    "no subscription with id {}",
+                    request.subscription_id
+                )).into());
             };
-            socket.send(Message::Text(serde_json::to_string(&response)?)).await?;
+            stream.abort();
+            conn.send_message(MessageType::Unsubscribed, &request).await?;
         }
-        _ => {
-            debug!("Received unhandled message type: {:?}", message.message_type);
+        other => {
+            debug!("Received unhandled message type: {:?}", other);
+            let error = ErrorMessage {
+                message_type: Some(other),
+                ..ErrorMessage::new(
+                    ErrorCode::UnsupportedMessage,
+                    format!("{:?} messages are not accepted from clients", other),
+                )
+            };
+            conn.send_error(error).await?;
         }
     }
     
     Ok(())
 }
 
-async fn handle_binary_message(
-    socket: &mut WebSocket,
-    state: &Arc<AppState>,
-    data: Vec<u8>,
+async fn run_inference(
+    state: &AppState,
+    model: &str,
+    data: serde_json::Value,
+    seed: Option<u64>,
+    client: Option<&str>,
+) -> anyhow::Result<(ModelType, String, serde_json::Value)> {
+    let inference = state.ml_engine
+        .infer_blocking(model.to_string(), Payload::Json(data), seed, client.map(str::to_string))
+        .await?;
+    Ok((inference.model_type, inference.version, inference.output.to_json()?))
+}
+
+// A subscription with its model resolved
+struct Stream {
+    request: SubscribeRequest,
+    model: String,
+    model_type: ModelType,
+}
+
+async fn subscribe(conn: &Connection, request: SubscribeRequest) -> anyhow::Result<()> {
+    let model = resolve_model(request.model.as_deref(), request.model_type)?.to_string();
+    let model_type = conn.state.ml_engine.model_kind(&model)?;
+    if request.alerts_only && model_type != ModelType::AnomalyDetection {
+        return Err(ModelError::InvalidInput("alerts_only applies to anomaly_detection streams".to_string()).into());
+    }
+    // Every tick would fold the same reading into the baseline until it looks normal
+    if request.data.is_some() && model_type == ModelType::AnomalyDetection {
+        return Err(ModelError::InvalidInput(
+            "anomaly_detection streams cannot take data, they update the baseline on every run".to_string(),
+        ).into());
+    }
+    let rate_hz = request.rate_hz.unwrap_or(DEFAULT_STREAM_RATE_HZ);
+    if request.data.is_some() && !(rate_hz > 0.0 && rate_hz <= MAX_STREAM_RATE_HZ) {
+        return Err(ModelError::InvalidInput(format!(
+            "rate_hz must be in (0, {}], got {}",
+            MAX_STREAM_RATE_HZ, rate_hz
+        )).into());
+    }
+
+    {
+        let subscriptions = conn.subscriptions.lock().unwrap();
+        if subscriptions.contains_key(&request.subscription_id) {
+            return Err(ModelError::InvalidInput(format!(
+                "subscription id {} is already in use",
+                request.subscription_id
+            )).into());
+        }
+        if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return Err(ModelError::InvalidInput(format!(
+                "at most {} subscriptions per connection",
+                MAX_SUBSCRIPTIONS
+            )).into());
+        }
+    }
+
+    // Acknowledge before the first update can be pushed
+    let ack = serde_json::json!({
+        "subscription_id": request.subscription_id,
+        "model_type": model_type,
+        "model": model,
+        "rate_hz": request.data.as_ref().map(|_| rate_hz),
+        "alerts_only": request.alerts_only,
+    });
+    conn.send_message(MessageType::Subscribed, &ack).await?;
+
+    // Held across the spawn so a stream that ends immediately cannot deregister before it is registered
+    let mut subscriptions = conn.subscriptions.lock().unwrap();
+    let subscription_id = request.subscription_id.clone();
+    let stream_conn = conn.clone();
+    let stream = Stream { request, model, model_type };
+    let task = tokio::spawn(async move {
+        let id = stream.request.subscription_id.clone();
+        let result = match stream.request.data.clone() {
+            Some(data) => stream_periodic(&stream_conn, &stream, data, rate_hz).await,
+            None => stream_results(&stream_conn, &stream).await,
+        };
+        stream_conn.subscriptions.lock().unwrap().remove(&id);
+
+        if let Err(e) = result {
+            let error = ErrorMessage {
+                code: error_code(&e, MessageType::Subscribe),
+                message: e.to_string(),
+                message_type: Some(MessageType::Subscribe),
+                model_type: Some(stream.model_type),
+                request_id: Some(id),
+            };
+            let _ = stream_conn.send_error(error).await;
+        }
+    });
+    subscriptions.insert(subscription_id, task.abort_handle());
+    Ok(())
+}
+
+fn passes_filter(stream: &Stream, prediction: &serde_json::Value) -> bool {
+    !stream.request.alerts_only || prediction.get("is_anomaly").and_then(|v| v.as_bool()) == Some(true)
+}
+
+async fn push_update(
+    conn: &Connection,
+    stream: &Stream,
+    version: String,
+    prediction: serde_json::Value,
 ) -> anyhow::Result<()> {
-    // Handle binary messages with bincode for efficiency
-    let message: WebSocketMessage = bincode::deserialize(&data)?;
-    
-    // Process similar to text messages but with binary response
-    // For brevity, we'll just convert to text handling for now
-    let text = serde_json::to_string(&message)?;
-    handle_text_message(socket, state, text).await
-}
\ No newline at end of file
+    let update = SubscriptionUpdate {
+        subscription_id: stream.request.subscription_id.clone(),
+        model_type: stream.model_type,
+        model: stream.model.clone(),
+        version,
+        prediction,
+        timestamp: chrono::Utc::now(),
+    };
+    conn.send_message(MessageType::SubscriptionUpdate, &update).await
+}
+
+async fn stream_periodic(
+    conn: &Connection,
+    stream: &Stream,
+    data: serde_json::Value,
+    rate_hz: f32,
+) -> anyhow::Result<()> {
+    let mut ticker = tokio::time::interval(Duration::from_secs_f32(1.0 / rate_hz));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let (_, version, prediction) =
+            run_inference(&conn.state, &stream.model, data.clone(), None, Some(&conn.id)).await?;
+        if passes_filter(stream, &prediction) {
+            push_update(conn, stream, version, prediction).await?;
+        }
+    }
+}
+
+async fn stream_results(conn: &Connection, stream: &Stream) -> anyhow::Result<()> {
+    let mut results = conn.state.ml_engine.subscribe_results();
+    loop {
+        match results.recv().await {
+            Ok(event) if event.model == stream.model && passes_filter(stream, &event.prediction) => {
+                push_update(conn, stream, event.version, event.prediction).await?;
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Subscription {} on {} skipped {} results", stream.request.subscription_id, conn.id, skipped);
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod handlers;
+pub mod ml;
+pub mod models;
+pub mod state;
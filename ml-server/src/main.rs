@@ -1,26 +1,43 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ws::{WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, State,
     },
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
-use std::{net::SocketAddr, sync::Arc};
+use clap::Parser;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use tower_http::cors::CorsLayer;
-use tracing::{info, warn};
+use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-mod handlers;
-mod ml;
-mod models;
-mod state;
+use ml_server::{
+    handlers,
+    ml::engine::EngineOptions,
+    models::binary::{BINCODE_PROTOCOL, MSGPACK_PROTOCOL},
+    state::AppState,
+};
 
-use crate::state::AppState;
+// Each flag can also be set through the environment variable next to it
+#[derive(Debug, Parser)]
+#[command(version, about = "Real-time inference server for the ML demo")]
+struct Cli {
+    #[arg(long, env = "MODEL_DIR", help = "Directory of <name>.mlmodel artifacts, loaded at startup and written by saves")]
+    model_dir: Option<PathBuf>,
+    #[arg(long, env = "MODEL_CONFIG", help = "JSON file listing the models to register")]
+    model_config: Option<PathBuf>,
+    #[arg(long, env = "ANOMALY_FOREST_PATH", help = "Where a trained isolation forest is saved and loaded from")]
+    anomaly_forest: Option<PathBuf>,
+    #[arg(long, env = "INFERENCE_SEED", help = "Seed every stochastic model for reproducible output")]
+    seed: Option<u64>,
+}
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+
     // Initialize tracing
     tracing_subscriber::registry()
         .with(
@@ -33,14 +50,76 @@ async fn main() {
     info!("Starting ML Server...");
 
     // Initialize application state
-    let app_state = Arc::new(AppState::new().await);
+    let options = EngineOptions {
+        model_dir: cli.model_dir,
+        model_config: cli.model_config,
+        anomaly_forest_path: cli.anomaly_forest,
+        seed: cli.seed,
+    };
+    let app_state = Arc::new(AppState::new(options).await);
 
     // Build our application with routes
     let app = Router::new()
         .route("/health", get(health_check))
         .route("/ws", get(websocket_handler))
-        .route("/api/models", get(handlers::rest::list_models))
-        .route("/api/inference/:model", post(handlers::rest::inference))
+        .route(
+            "/api/models",
+            get(handlers::rest::list_models).post(handlers::rest::register_model),
+        )
+        .route("/api/models/:name", delete(handlers::rest::unregister_model))
+        .route("/api/models/:name/reload", post(handlers::rest::reload_model))
+        .route("/api/models/:name/rollback", post(handlers::rest::rollback_model))
+        .route("/api/models/:name/versions", get(handlers::rest::model_versions))
+        .route("/api/models/:name/save", post(handlers::rest::save_model))
+        .route(
+            "/api/models/:name/experiment",
+            post(handlers::rest::start_experiment)
+                .patch(handlers::rest::update_experiment)
+                .delete(handlers::rest::stop_experiment),
+        )
+        .route("/api/models/:name/experiment/promote", post(handlers::rest::promote_candidate))
+        .route(
+            "/api/models/:name/shadow",
+            get(handlers::rest::shadow_report)
+                .post(handlers::rest::start_shadow)
+                .delete(handlers::rest::stop_shadow),
+        )
+        .route("/api/models/:name/stats", get(handlers::rest::model_stats))
+        .route("/api/models/:name/classes", get(handlers::rest::model_classes))
+        .route(
+            "/api/models/:name/baselines",
+            get(handlers::rest::anomaly_baselines).delete(handlers::rest::reset_anomaly_baselines),
+        )
+        .route(
+            "/api/models/:name/baselines/:sensor_type",
+            delete(handlers::rest::reset_anomaly_baseline),
+        )
+        .route("/api/models/:name/train", post(handlers::rest::train_anomaly_forest))
+        .route(
+            "/api/models/:name/forest",
+            get(handlers::rest::anomaly_forest).delete(handlers::rest::clear_anomaly_forest),
+        )
+        .route(
+            "/api/models/:name/threshold",
+            get(handlers::rest::anomaly_threshold).put(handlers::rest::update_anomaly_threshold),
+        )
+        .route(
+            "/api/models/:name/threshold/:sensor_type",
+            delete(handlers::rest::clear_anomaly_sensor_threshold),
+        )
+        .route(
+            "/api/inference/:model",
+            post(handlers::rest::inference).layer(DefaultBodyLimit::max(handlers::rest::MAX_UPLOAD_BYTES)),
+        )
+        .route("/api/inference/:model/batch", post(handlers::rest::batch_inference))
+        .route(
+            "/api/pipeline/:model",
+            post(handlers::rest::pipeline).layer(DefaultBodyLimit::max(handlers::rest::MAX_UPLOAD_BYTES)),
+        )
+        .route(
+            "/api/inference/:model/upload",
+            post(handlers::rest::upload_inference).layer(DefaultBodyLimit::max(handlers::rest::MAX_UPLOAD_BYTES)),
+        )
         .layer(CorsLayer::permissive())
         .with_state(app_state);
 
@@ -60,7 +139,8 @@ async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, state))
+    ws.protocols([BINCODE_PROTOCOL, MSGPACK_PROTOCOL])
+        .on_upgrade(|socket| websocket(socket, state))
 }
 
 async fn websocket(socket: WebSocket, state: Arc<AppState>) {
@@ -0,0 +1,185 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path, PathBuf};
+
+use crate::models::{ModelError, ModelType};
+
+use super::model::fnv1a;
+
+// Artifacts in a model directory are named <model name>.mlmodel
+pub const ARTIFACT_EXTENSION: &str = "mlmodel";
+
+// Layout, integers little endian:
+//   magic           8 bytes  "MLMODEL\0"
+//   format version  u16
+//   body length     u32
+//   body            bincode encoded ArtifactBody
+//   checksum        u64      FNV-1a over everything before it
+const MAGIC: &[u8; 8] = b"MLMODEL\0";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+const CHECKSUM_LEN: usize = 8;
+
+// Paths from configs and requests, artifacts and onnx files alike, name files in the
+// model directory and may not leave it
+pub fn resolve_path(model_dir: Option<&Path>, path: &Path) -> Result<PathBuf, ModelError> {
+    let dir = model_dir.ok_or_else(|| {
+        ModelError::InvalidInput(format!(
+            "cannot use {}, file paths need the server to have a model directory",
+            path.display()
+        ))
+    })?;
+    let inside = path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !inside || path.file_name().is_none() {
+        return Err(ModelError::InvalidInput(format!(
+            "{} must be a file path relative to the model directory, without '..'",
+            path.display()
+        )));
+    }
+    Ok(dir.join(path))
+}
+
+// One model version as saved on disk
+#[derive(Debug, Clone)]
+pub struct ModelArtifact {
+    pub kind: ModelType,
+    pub version: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    // Whatever the kind's with_params takes
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+// What a save wrote
+#[derive(Debug, Serialize)]
+pub struct ArtifactInfo {
+    pub path: PathBuf,
+    pub kind: ModelType,
+    pub version: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// bincode cannot encode serde_json::Value, so params are carried as JSON text
+#[derive(Serialize, Deserialize)]
+struct ArtifactBody {
+    kind: ModelType,
+    version: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    params: String,
+}
+
+impl ModelArtifact {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let body = bincode::serialize(&ArtifactBody {
+            kind: self.kind,
+            version: self.version.clone(),
+            created_at: self.created_at,
+            params: serde_json::to_string(&self.params)?,
+        })?;
+        let body_len = u32::try_from(body.len()).context("artifact body over 4 GiB")?;
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + body.len() + CHECKSUM_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&body_len.to_le_bytes());
+        bytes.extend_from_slice(&body);
+        bytes.extend_from_slice(&fnv1a(&bytes).to_le_bytes());
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_LEN + CHECKSUM_LEN || &bytes[..MAGIC.len()] != MAGIC {
+            bail!("not a model artifact");
+        }
+        let format_version = u16::from_le_bytes([bytes[8], bytes[9]]);
+        if format_version != FORMAT_VERSION {
+            bail!("unsupported artifact format version {}, expected {}", format_version, FORMAT_VERSION);
+        }
+        let body_len = u32::from_le_bytes([bytes[10], bytes[11], bytes[12], bytes[13]]) as usize;
+        if bytes.len() != HEADER_LEN + body_len + CHECKSUM_LEN {
+            bail!("artifact is truncated or has trailing data");
+        }
+
+        let (content, checksum) = bytes.split_at(HEADER_LEN + body_len);
+        let checksum = u64::from_le_bytes(checksum.try_into()?);
+        if fnv1a(content) != checksum {
+            bail!("artifact checksum mismatch");
+        }
+
+        let body: ArtifactBody = bincode::deserialize(&content[HEADER_LEN..])?;
+        Ok(Self {
+            kind: body.kind,
+            version: body.version,
+            created_at: body.created_at,
+            params: serde_json::from_str(&body.params)?,
+        })
+    }
+
+    // Written to a temporary file and renamed, so a reload never sees half an artifact
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, self.encode()?)?;
+        std::fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::decode(&std::fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact() -> ModelArtifact {
+        let mut params = serde_json::Map::new();
+        params.insert("threshold".to_string(), serde_json::json!(2.5));
+        params.insert("sensors".to_string(), serde_json::json!({"temperature": 3.0}));
+        ModelArtifact {
+            kind: ModelType::AnomalyDetection,
+            version: "1.2.0".to_string(),
+            created_at: chrono::Utc::now(),
+            params,
+        }
+    }
+
+    #[test]
+    fn decode_reads_back_what_encode_wrote() {
+        let original = artifact();
+        let decoded = ModelArtifact::decode(&original.encode().unwrap()).unwrap();
+        assert_eq!(decoded.kind, original.kind);
+        assert_eq!(decoded.version, original.version);
+        assert_eq!(decoded.created_at, original.created_at);
+        assert_eq!(decoded.params, original.params);
+    }
+
+    #[test]
+    fn a_flipped_body_byte_fails_the_checksum() {
+        let mut bytes = artifact().encode().unwrap();
+        bytes[HEADER_LEN + 2] ^= 0x01;
+        let error = ModelArtifact::decode(&bytes).unwrap_err();
+        assert!(error.to_string().contains("checksum mismatch"), "{}", error);
+    }
+
+    #[test]
+    fn truncated_input_is_rejected() {
+        let bytes = artifact().encode().unwrap();
+        for len in [0, MAGIC.len(), HEADER_LEN, bytes.len() - 1] {
+            assert!(ModelArtifact::decode(&bytes[..len]).is_err(), "decoded {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn other_files_and_versions_are_rejected() {
+        let mut bytes = artifact().encode().unwrap();
+        bytes[0] = b'X';
+        assert!(ModelArtifact::decode(&bytes).unwrap_err().to_string().contains("not a model artifact"));
+
+        let mut bytes = artifact().encode().unwrap();
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(ModelArtifact::decode(&bytes).unwrap_err().to_string().contains("unsupported artifact format version"));
+    }
+}
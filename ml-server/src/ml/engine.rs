@@ -1,69 +1,833 @@
 use anyhow::Result;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::sync::{broadcast, Semaphore};
+use tracing::{info, warn};
 
 use crate::models::{
-    trajectory::{TrajectoryPredictor, TrajectoryPredictionInput, TrajectoryPredictionOutput},
-    anomaly::{AnomalyDetector, AnomalyDetectionInput, AnomalyDetectionOutput},
-    objects::{ObjectDetector, ObjectDetectionInput, ObjectDetectionOutput},
-    fusion::{SensorFusion, FusionInput, FusionOutput},
-    ModelType,
+    anomaly::{AnomalyDetector, AnomalyThresholdConfig, SensorBaseline},
+    isolation_forest::{ForestSummary, ForestTrainingInput, IsolationForest},
+    objects::{ObjectDetectionOutput, ObjectDetector},
+    taxonomy::{ClassList, Taxonomy},
+    trajectory::TrajectoryPredictionInput,
+    InferenceEvent, ModelChange, ModelError, ModelType, ModelUpdate,
 };
 
+use super::{
+    artifact::{resolve_path, ArtifactInfo, ARTIFACT_EXTENSION},
+    experiment::{ExperimentConfig, ExperimentInfo, ModelStats, TrafficSplit},
+    model::{Payload, Prediction},
+    pipeline::{PipelineRequest, PipelineResponse, TrackHistories, TrackTrajectory},
+    shadow::{Shadow, ShadowConfig, ShadowReport},
+    registry::{
+        ModelConfig, ModelInfo, ModelRegistry, ModelStatus, ModelVersion, ModelVersions, ModelsConfig,
+        RegisteredModel, VersionRecord,
+    },
+};
+
+const MODEL_UPDATE_CAPACITY: usize = 64;
+const INFERENCE_EVENT_CAPACITY: usize = 256;
+const MAX_VERSION_RECORDS: usize = 100;
+const MAX_SHADOW_IN_FLIGHT: usize = 32;
+
+// Startup settings, from the command line or the environment
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    // Holds <name>.mlmodel artifacts, loaded at startup and written by saves
+    pub model_dir: Option<PathBuf>,
+    pub model_config: Option<PathBuf>,
+    // Where a trained isolation forest is persisted and reloaded from
+    pub anomaly_forest_path: Option<PathBuf>,
+    pub seed: Option<u64>,
+}
+
+// A prediction with the model version that made it
+pub struct Inference {
+    pub model_type: ModelType,
+    pub version: String,
+    pub output: Box<dyn Prediction>,
+}
+
 pub struct MLEngine {
-    trajectory_predictor: Arc<RwLock<TrajectoryPredictor>>,
-    anomaly_detector: Arc<RwLock<AnomalyDetector>>,
-    object_detector: Arc<RwLock<ObjectDetector>>,
-    sensor_fusion: Arc<RwLock<SensorFusion>>,
+    registry: RwLock<ModelRegistry>,
+    versions: ModelVersions,
+    model_dir: Option<PathBuf>,
+    // Where a trained isolation forest is persisted and reloaded from, if configured
+    anomaly_forest_path: Option<PathBuf>,
+    // Shadow predictions running on the blocking pool, across all models
+    shadow_slots: Arc<Semaphore>,
+    // Where pipeline requests build up each tracked object's trajectory history
+    track_histories: TrackHistories,
+    // Server-wide seed, set to make every stochastic model reproducible
+    seed: Option<u64>,
+    updates: broadcast::Sender<ModelUpdate>,
+    // Every prediction made, for clients streaming another client's results
+    results: broadcast::Sender<InferenceEvent>,
+}
+
+// Defaults keep their name for their own kind only
+fn reserved_kind(name: &str, kind: ModelType) -> Option<ModelType> {
+    ModelType::ALL.into_iter().find(|k| k.default_name() == name && *k != kind)
+}
+
+// Points the model at its file in the model directory, where saves go and reloads read
+// from. With `load_saved` an existing file there is loaded in place of inline params.
+fn prepare_model(mut config: ModelConfig, model_dir: Option<&Path>, load_saved: bool) -> Result<RegisteredModel, ModelError> {
+    if let Some(path) = &config.artifact {
+        config.artifact = Some(resolve_path(model_dir, path)?);
+    }
+    let saved = model_dir.map(|dir| dir.join(format!("{}.{}", config.name, ARTIFACT_EXTENSION)));
+    if config.artifact.is_none() && load_saved {
+        config.artifact = saved.clone().filter(|path| path.exists());
+    }
+    let mut model = RegisteredModel::from_config(config, model_dir)?;
+    model.artifact = model.artifact.or(saved);
+    Ok(model)
 }
 
 impl MLEngine {
-    pub async fn new() -> Self {
-        Self {
-            trajectory_predictor: Arc::new(RwLock::new(
-                TrajectoryPredictor::new().expect("Failed to create trajectory predictor")
-            )),
-            anomaly_detector: Arc::new(RwLock::new(AnomalyDetector::new())),
-            object_detector: Arc::new(RwLock::new(ObjectDetector::new())),
-            sensor_fusion: Arc::new(RwLock::new(SensorFusion::new())),
+    pub async fn new(options: EngineOptions, versions: ModelVersions) -> Self {
+        if let Some(seed) = options.seed {
+            info!("Deterministic inference enabled with seed {}", seed);
+        }
+        let model_dir = options.model_dir.as_deref();
+
+        // Configured models first, so the config can also tune the default instances. An
+        // artifact saved in the model directory takes over from inline params.
+        let mut registry = ModelRegistry::new();
+        if let Some(path) = &options.model_config {
+            Self::register_configured(&mut registry, path, model_dir);
+        }
+        if let Some(dir) = model_dir {
+            Self::register_directory(&mut registry, dir);
+        }
+        for kind in ModelType::ALL {
+            if !registry.contains(kind.default_name()) {
+                let config = ModelConfig {
+                    name: kind.default_name().to_string(),
+                    kind,
+                    params: Default::default(),
+                    enabled: true,
+                    artifact: None,
+                };
+                // A broken saved artifact must not keep the server from starting
+                let model = prepare_model(config.clone(), model_dir, true).unwrap_or_else(|e| {
+                    warn!("Falling back to built-in {} params: {}", config.name, e);
+                    prepare_model(config, model_dir, false).expect("Failed to create default model")
+                });
+                registry.register(model);
+            }
+        }
+
+        let engine = Self {
+            registry: RwLock::new(registry),
+            versions,
+            model_dir: options.model_dir,
+            anomaly_forest_path: options.anomaly_forest_path,
+            shadow_slots: Arc::new(Semaphore::new(MAX_SHADOW_IN_FLIGHT)),
+            track_histories: TrackHistories::default(),
+            seed: options.seed,
+            updates: broadcast::channel(MODEL_UPDATE_CAPACITY).0,
+            results: broadcast::channel(INFERENCE_EVENT_CAPACITY).0,
+        };
+        let models: Vec<_> = engine.registry.read().unwrap().iter().cloned().collect();
+        for model in models {
+            let active = model.active();
+            engine.load_persisted_forest(&model, &active);
+            engine.record_version(&model, &active, ModelChange::Registered);
+        }
+        engine
+    }
+
+    // A registered anomaly model, typed for threshold, baseline and forest management.
+    // Looked up per call since reloads and rollbacks swap in a different instance.
+    fn anomaly_detector(&self, name: &str) -> Result<Arc<RwLock<AnomalyDetector>>, ModelError> {
+        self.model(name)?
+            .active()
+            .model
+            .clone()
+            .into_any()
+            .downcast()
+            .map_err(|_| ModelError::InvalidInput(format!("model {} is not an anomaly detection model", name)))
+    }
+
+    // A trained forest belongs to the default anomaly model, whichever version is active
+    fn load_persisted_forest(&self, model: &RegisteredModel, version: &ModelVersion) {
+        if model.name != ModelType::AnomalyDetection.default_name() {
+            return;
+        }
+        let Some(path) = self.anomaly_forest_path.as_ref().filter(|p| p.exists()) else {
+            return;
+        };
+        let Ok(detector) = version.model.clone().into_any().downcast::<RwLock<AnomalyDetector>>() else {
+            return;
+        };
+        // A forest saved in the model's artifact wins
+        if detector.read().unwrap().forest().is_some() {
+            return;
+        }
+        match IsolationForest::load(path) {
+            Ok(forest) => {
+                info!("Loaded anomaly isolation forest from {}", path.display());
+                detector.write().unwrap().set_forest(Some(forest));
+            }
+            Err(e) => warn!("Failed to load anomaly forest from {}: {}", path.display(), e),
+        }
+    }
+
+    fn record_version(&self, model: &RegisteredModel, version: &ModelVersion, change: ModelChange) {
+        let mut versions = self.versions.write().unwrap();
+        let history = versions.entry(model.name.clone()).or_default();
+        history.push(VersionRecord {
+            version: version.version.clone(),
+            change,
+            source: version.source.clone(),
+            timestamp: chrono::Utc::now(),
+        });
+        if history.len() > MAX_VERSION_RECORDS {
+            history.remove(0);
         }
     }
 
-    pub async fn predict_trajectory(
+    // Bad entries are skipped so one typo does not take the other models down
+    fn register_configured(registry: &mut ModelRegistry, path: &Path, model_dir: Option<&Path>) {
+        let config: ModelsConfig = match std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?))
+        {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Failed to read model config {}: {}", path.display(), e);
+                return;
+            }
+        };
+
+        for config in config.models {
+            let name = config.name.clone();
+            let kind = config.kind;
+            if let Some(reserved) = reserved_kind(&name, kind) {
+                warn!("Skipping model {}: the name is reserved for the default {:?} model", name, reserved);
+                continue;
+            }
+            match prepare_model(config, model_dir, true) {
+                Ok(model) => {
+                    info!("Registered {:?} model {}", kind, name);
+                    registry.register(model);
+                }
+                Err(e) => warn!("Skipping model {}: {}", name, e),
+            }
+        }
+    }
+
+    // Artifacts of models the config does not mention, named after the file
+    fn register_directory(registry: &mut ModelRegistry, dir: &Path) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read model directory {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == ARTIFACT_EXTENSION))
+            .collect();
+        paths.sort();
+
+        for path in paths {
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string) else {
+                continue;
+            };
+            // Default instances pick up their artifact when they are created
+            if registry.contains(&name) || ModelType::ALL.iter().any(|kind| kind.default_name() == name) {
+                continue;
+            }
+            match RegisteredModel::from_artifact(name.clone(), path.clone(), Some(dir)) {
+                Ok(model) => {
+                    info!("Registered {:?} model {} from {}", model.kind, name, path.display());
+                    registry.register(model);
+                }
+                Err(e) => warn!("Skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    pub fn subscribe_results(&self) -> broadcast::Receiver<InferenceEvent> {
+        self.results.subscribe()
+    }
+
+    fn publish_result(&self, model: &RegisteredModel, version: &str, prediction: serde_json::Value) {
+        let _ = self.results.send(InferenceEvent {
+            model_type: model.kind,
+            model: model.name.clone(),
+            version: version.to_string(),
+            prediction,
+            timestamp: chrono::Utc::now(),
+        });
+    }
+
+    pub fn subscribe_updates(&self) -> broadcast::Receiver<ModelUpdate> {
+        self.updates.subscribe()
+    }
+
+    fn publish(&self, model_type: ModelType, model: &str, change: ModelChange, details: serde_json::Value) {
+        // Sending only fails when nobody is listening, which is fine
+        let _ = self.updates.send(ModelUpdate {
+            model_type,
+            model: model.to_string(),
+            change,
+            details,
+            timestamp: chrono::Utc::now(),
+        });
+    }
+
+    fn model(&self, name: &str) -> Result<Arc<RegisteredModel>, ModelError> {
+        self.registry
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| ModelError::UnknownModel(name.to_string()))
+    }
+
+    pub fn model_kind(&self, name: &str) -> Result<ModelType, ModelError> {
+        Ok(self.model(name)?.kind)
+    }
+
+    // Every transport goes through here. A request seed takes precedence over the server seed,
+    // the client id keeps sticky experiments on one version. Seeded predictions depend only on
+    // the input, except that anomaly baselines and object tracks learn from every request:
+    // those repeat for the same sequence of requests rather than for each one alone.
+    pub fn infer(&self, name: &str, input: Payload, seed: Option<u64>, client: Option<&str>) -> Result<Inference> {
+        let model = self.model(name)?;
+        if model.status == ModelStatus::Disabled {
+            return Err(ModelError::Unavailable(name.to_string()).into());
+        }
+        let seed = seed.or(self.seed);
+        let (version, experimenting) = model.route(client, seed.map(|seed| (seed, &input)));
+        let shadow = model.shadow_for(&version);
+        // A shadow gets the same seed, so the two versions see the same noise and any
+        // divergence comes from the model
+        let seed = seed.or_else(|| shadow.as_ref().map(|_| rand::random()));
+        let shadow_input = shadow.as_ref().map(|_| input.clone());
+        let start = std::time::Instant::now();
+        let output = match version.model.predict(input, seed) {
+            Ok(output) => output,
+            Err(e) => {
+                model.record_error(&version.version);
+                return Err(e);
+            }
+        };
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        // Skip serializing when no one is streaming and no experiment or shadow needs the output
+        let prediction = if experimenting || shadow.is_some() || self.results.receiver_count() > 0 {
+            output.to_json()
+                .map_err(|e| warn!("Failed to serialize {} result: {}", model.name, e))
+                .ok()
+        } else {
+            None
+        };
+        model.record(&version.version, latency_ms, prediction.as_ref().filter(|_| experimenting));
+        if let (Some(shadow), Some(input), Some(primary)) = (shadow, shadow_input, &prediction) {
+            self.spawn_shadow(model.kind, shadow, input, seed, primary.clone());
+        }
+        if let Some(prediction) = prediction {
+            self.publish_result(&model, &version.version, prediction);
+        }
+        Ok(Inference {
+            model_type: model.kind,
+            version: version.version.clone(),
+            output,
+        })
+    }
+
+    // For async callers. Decoding and running a model can take a while, so it happens on
+    // the blocking pool rather than a runtime worker.
+    pub async fn infer_blocking(
+        self: &Arc<Self>,
+        name: String,
+        input: Payload,
+        seed: Option<u64>,
+        client: Option<String>,
+    ) -> Result<Inference> {
+        let engine = self.clone();
+        tokio::task::spawn_blocking(move || engine.infer(&name, input, seed, client.as_deref())).await?
+    }
+
+    // Detects and tracks objects in one frame of a stream, then predicts the path of every
+    // tracked object from the centers it had in earlier frames. A failed trajectory is
+    // reported for its track, only a failed detection fails the request.
+    pub fn pipeline(
         &self,
-        input: TrajectoryPredictionInput,
-    ) -> Result<TrajectoryPredictionOutput> {
-        let predictor = self.trajectory_predictor.read().await;
-        Ok(predictor.predict(&input)?)
+        detector: &str,
+        request: PipelineRequest,
+        seed: Option<u64>,
+        client: Option<&str>,
+    ) -> Result<PipelineResponse> {
+        let start = std::time::Instant::now();
+        let trajectory_model = request
+            .trajectory_model
+            .as_deref()
+            .unwrap_or(ModelType::TrajectoryPrediction.default_name());
+        for (name, kind) in [(detector, ModelType::ObjectDetection), (trajectory_model, ModelType::TrajectoryPrediction)] {
+            let actual = self.model_kind(name)?;
+            if actual != kind {
+                return Err(ModelError::InvalidInput(format!("model {} is {:?}, expected {:?}", name, actual, kind)).into());
+            }
+        }
+        let stream = request.frame
+            .get("stream_id")
+            .and_then(|stream| stream.as_str())
+            .ok_or_else(|| ModelError::InvalidInput("pipeline frames need a stream_id to track objects in".to_string()))?
+            .to_string();
+        let timestamp = request.frame
+            .get("timestamp")
+            .and_then(|timestamp| timestamp.as_i64())
+            .ok_or_else(|| ModelError::InvalidInput("pipeline frames need a timestamp".to_string()))?;
+
+        let inference = self.infer(detector, Payload::Json(request.frame), seed, client)?;
+        let detection: ObjectDetectionOutput = serde_json::from_value(inference.output.to_json()?)?;
+
+        let options = request.trajectory;
+        let trajectories = self.track_histories
+            .record(detector, &inference.version, &stream, timestamp, &detection)
+            .into_iter()
+            .map(|(track_id, history)| {
+                let history_points = history.len();
+                if history_points < 2 {
+                    return Ok(TrackTrajectory { track_id, history_points, prediction: None, error: None });
+                }
+                let input = serde_json::to_value(TrajectoryPredictionInput {
+                    history,
+                    prediction_horizon: options.prediction_horizon,
+                    prediction_horizon_ms: options.prediction_horizon_ms,
+                    output_interval_ms: options.output_interval_ms,
+                    motion_model: options.motion_model,
+                })?;
+                let (prediction, error) = match self.infer(trajectory_model, Payload::Json(input), seed, client) {
+                    Ok(inference) => (Some(inference.output.to_json()?), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                Ok(TrackTrajectory { track_id, history_points, prediction, error })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PipelineResponse {
+            model: detector.to_string(),
+            version: inference.version,
+            detection,
+            trajectories,
+            latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+            timestamp: chrono::Utc::now(),
+        })
     }
 
-    pub async fn detect_anomaly(
+    // On the blocking pool so the candidate never delays the response it mirrors. Past the
+    // in-flight limit requests go unshadowed rather than queue up behind a slow candidate.
+    fn spawn_shadow(
         &self,
-        input: AnomalyDetectionInput,
-    ) -> Result<AnomalyDetectionOutput> {
-        let detector = self.anomaly_detector.read().await;
-        Ok(detector.detect(&input))
+        kind: ModelType,
+        shadow: Arc<Shadow>,
+        input: Payload,
+        seed: Option<u64>,
+        primary: serde_json::Value,
+    ) {
+        let Ok(permit) = self.shadow_slots.clone().try_acquire_owned() else {
+            shadow.record_dropped();
+            return;
+        };
+        tokio::task::spawn_blocking(move || {
+            shadow.run(kind, input, seed, &primary);
+            drop(permit);
+        });
+    }
+
+    pub fn models(&self) -> Vec<ModelInfo> {
+        self.registry.read().unwrap().iter().map(|model| model.info()).collect()
+    }
+
+    pub fn register_model(&self, config: ModelConfig) -> Result<ModelInfo> {
+        // A leftover artifact under the same name is overwritten by the next save, not loaded
+        let model = prepare_model(config, self.model_dir.as_deref(), false)?;
+        let info = model.info();
+        {
+            let mut registry = self.registry.write().unwrap();
+            if registry.contains(&model.name) {
+                return Err(ModelError::InvalidInput(format!("model {} is already registered", model.name)).into());
+            }
+            self.record_version(&model, &model.active(), ModelChange::Registered);
+            registry.register(model);
+        }
+        self.publish(info.kind, &info.name, ModelChange::Registered, serde_json::to_value(&info)?);
+        Ok(info)
+    }
+
+    // Reads the model's artifact again and swaps it in. Requests already running finish
+    // on the version they started with.
+    pub fn reload_model(&self, name: &str) -> Result<ModelInfo> {
+        let model = self.model(name)?;
+        let version = model.load_artifact(None)?;
+        self.load_persisted_forest(&model, &version);
+        self.record_version(&model, &version, ModelChange::Reloaded);
+        let stopped = model.swap(Arc::new(version));
+
+        let info = model.info();
+        info!("Reloaded model {} at version {}", name, info.version);
+        self.publish(model.kind, &model.name, ModelChange::Reloaded, serde_json::to_value(&info)?);
+        self.publish_stopped_shadow(&model, stopped)?;
+        Ok(info)
+    }
+
+    // Restores the version the last reload replaced, with whatever state it had then
+    pub fn rollback_model(&self, name: &str) -> Result<ModelInfo> {
+        let model = self.model(name)?;
+        let (restored, stopped) = model.rollback()?;
+        self.record_version(&model, &restored, ModelChange::RolledBack);
+
+        let info = model.info();
+        info!("Rolled model {} back to version {}", name, info.version);
+        self.publish(model.kind, &model.name, ModelChange::RolledBack, serde_json::to_value(&info)?);
+        self.publish_stopped_shadow(&model, stopped)?;
+        Ok(info)
+    }
+
+    // The candidate is loaded from an artifact and serves its share of traffic next to the
+    // active version until it is promoted or stopped
+    pub fn start_experiment(&self, name: &str, config: ExperimentConfig) -> Result<ModelInfo> {
+        let model = self.model(name)?;
+        let candidate = model.load_artifact(config.artifact.as_deref())?;
+        self.load_persisted_forest(&model, &candidate);
+        let candidate = Arc::new(candidate);
+        model.start_experiment(candidate.clone(), config.split())?;
+        self.record_version(&model, &candidate, ModelChange::ExperimentStarted);
+
+        let info = model.info();
+        info!("Started experiment on {}: {:?}", name, info.experiment);
+        self.publish(model.kind, &model.name, ModelChange::ExperimentStarted, serde_json::to_value(&info)?);
+        Ok(info)
+    }
+
+    pub fn update_experiment(&self, name: &str, split: TrafficSplit) -> Result<ExperimentInfo> {
+        let model = self.model(name)?;
+        let experiment = model.update_split(split)?;
+        self.publish(model.kind, &model.name, ModelChange::ExperimentUpdated, serde_json::to_value(&experiment)?);
+        Ok(experiment)
+    }
+
+    pub fn stop_experiment(&self, name: &str) -> Result<ExperimentInfo> {
+        let model = self.model(name)?;
+        let experiment = model.stop_experiment()?.info();
+        self.publish(model.kind, &model.name, ModelChange::ExperimentStopped, serde_json::to_value(&experiment)?);
+        Ok(experiment)
+    }
+
+    pub fn promote_candidate(&self, name: &str) -> Result<ModelInfo> {
+        let model = self.model(name)?;
+        let (promoted, stopped) = model.promote()?;
+        self.record_version(&model, &promoted, ModelChange::Promoted);
+
+        let info = model.info();
+        info!("Promoted version {} of {}", info.version, name);
+        self.publish(model.kind, &model.name, ModelChange::Promoted, serde_json::to_value(&info)?);
+        self.publish_stopped_shadow(&model, stopped)?;
+        Ok(info)
+    }
+
+    // Shadows stop when their version becomes active, clients hear about it like a stop request
+    fn publish_stopped_shadow(&self, model: &RegisteredModel, shadow: Option<Arc<Shadow>>) -> Result<()> {
+        if let Some(shadow) = shadow {
+            let report = shadow.report();
+            info!("Stopped shadow of {} now that version {} is active", model.name, report.candidate_version);
+            self.publish(model.kind, &model.name, ModelChange::ShadowStopped, serde_json::to_value(&report)?);
+        }
+        Ok(())
+    }
+
+    // Label map of the active version, whichever backend it runs on
+    pub fn model_classes(&self, name: &str) -> Result<ClassList> {
+        let model = self.model(name)?;
+        let detector = model.active().model.clone().into_any();
+        let taxonomy: Option<Taxonomy> = match detector.downcast::<ObjectDetector>() {
+            Ok(detector) => Some(detector.taxonomy().clone()),
+            #[cfg(feature = "onnx")]
+            Err(detector) => detector
+                .downcast::<crate::models::onnx::OnnxDetector>()
+                .ok()
+                .map(|detector| detector.taxonomy().clone()),
+            #[cfg(not(feature = "onnx"))]
+            Err(_) => None,
+        };
+        let taxonomy = taxonomy.ok_or_else(|| {
+            ModelError::InvalidInput(format!("model {} is {:?}, it has no object classes", name, model.kind))
+        })?;
+        Ok(taxonomy.describe(name))
+    }
+
+    pub fn model_stats(&self, name: &str) -> Result<ModelStats> {
+        let model = self.model(name)?;
+        Ok(ModelStats {
+            name: model.name.clone(),
+            active_version: model.active().version.clone(),
+            experiment: model.experiment(),
+            versions: model.stats(),
+        })
+    }
+
+    // The candidate sees every request the active version serves from now on, until it is
+    // stopped or becomes active itself. The client only ever gets the serving version's output.
+    pub fn start_shadow(&self, name: &str, config: ShadowConfig) -> Result<ShadowReport> {
+        let model = self.model(name)?;
+        let candidate = model.load_artifact(config.artifact.as_deref())?;
+        self.load_persisted_forest(&model, &candidate);
+        let candidate = Arc::new(candidate);
+        let report = model.start_shadow(candidate.clone())?.report();
+        self.record_version(&model, &candidate, ModelChange::ShadowStarted);
+
+        info!("Shadowing {} with version {}", name, report.candidate_version);
+        self.publish(model.kind, &model.name, ModelChange::ShadowStarted, serde_json::to_value(&report)?);
+        Ok(report)
+    }
+
+    pub fn shadow_report(&self, name: &str) -> Result<ShadowReport> {
+        Ok(self.model(name)?.running_shadow()?.report())
+    }
+
+    // Returns the final metrics
+    pub fn stop_shadow(&self, name: &str) -> Result<ShadowReport> {
+        let model = self.model(name)?;
+        let report = model.stop_shadow()?.report();
+        self.publish(model.kind, &model.name, ModelChange::ShadowStopped, serde_json::to_value(&report)?);
+        Ok(report)
+    }
+
+    // Writes the active version to the model's artifact, where the next reload or restart
+    // picks it up. Without a new version label the active one is kept.
+    pub fn save_model(&self, name: &str, version: Option<String>) -> Result<ArtifactInfo> {
+        let model = self.model(name)?;
+        let path = model.artifact.clone().ok_or_else(|| {
+            ModelError::InvalidInput(format!("model {} has no artifact path, start the server with a model directory", name))
+        })?;
+        let active = model.active();
+        let artifact = active.to_artifact(model.kind, version.unwrap_or_else(|| active.version.clone()))?;
+        artifact.save(&path)?;
+
+        info!("Saved {} version {} to {}", name, artifact.version, path.display());
+        let info = ArtifactInfo {
+            path,
+            kind: artifact.kind,
+            version: artifact.version,
+            created_at: artifact.created_at,
+        };
+        self.publish(model.kind, &model.name, ModelChange::Saved, serde_json::to_value(&info)?);
+        Ok(info)
     }
 
-    pub async fn detect_objects(
+    // Default instances cannot be removed, every kind keeps one to fall back on
+    pub fn unregister_model(&self, name: &str) -> Result<()> {
+        if ModelType::ALL.iter().any(|kind| kind.default_name() == name) {
+            return Err(ModelError::InvalidInput(format!("{} is a default model and cannot be removed", name)).into());
+        }
+        let model = self.registry
+            .write()
+            .unwrap()
+            .unregister(name)
+            .ok_or_else(|| ModelError::UnknownModel(name.to_string()))?;
+        self.versions.write().unwrap().remove(name);
+        self.track_histories.forget(name);
+        self.publish(model.kind, name, ModelChange::Unregistered, serde_json::json!({ "name": name }));
+        Ok(())
+    }
+
+    pub async fn anomaly_baselines(&self, name: &str) -> Result<Vec<SensorBaseline>, ModelError> {
+        let anomaly_detector = self.anomaly_detector(name)?;
+        let detector = anomaly_detector.read().unwrap();
+        Ok(detector.baselines())
+    }
+
+    pub async fn reset_anomaly_baselines(&self, name: &str, sensor_type: Option<&str>) -> Result<usize, ModelError> {
+        let reset = {
+            let anomaly_detector = self.anomaly_detector(name)?;
+            let detector = anomaly_detector.read().unwrap();
+            detector.reset_baselines(sensor_type)
+        };
+        if reset > 0 {
+            let details = serde_json::json!({ "sensor_type": sensor_type, "reset": reset });
+            self.publish(ModelType::AnomalyDetection, name, ModelChange::BaselinesReset, details);
+        }
+        Ok(reset)
+    }
+
+    // Only the default model's forest is written to the forest path, other models keep
+    // theirs in the artifact a save writes
+    pub async fn train_anomaly_forest(&self, name: &str, input: ForestTrainingInput) -> Result<ForestSummary> {
+        // Checked before the fit, fetched again after it in case a reload swapped the instance
+        self.anomaly_detector(name)?;
+        // Fitting is CPU bound, keep it off the async workers
+        let forest = tokio::task::spawn_blocking(move || IsolationForest::fit(&input)).await??;
+        if let Some(path) = self.anomaly_forest_path.as_ref().filter(|_| name == ModelType::AnomalyDetection.default_name()) {
+            forest.save(path)?;
+            info!("Saved anomaly isolation forest to {}", path.display());
+        }
+
+        let summary = forest.summary();
+        self.anomaly_detector(name)?.write().unwrap().set_forest(Some(forest));
+        self.publish(ModelType::AnomalyDetection, name, ModelChange::ForestTrained, serde_json::to_value(&summary)?);
+        Ok(summary)
+    }
+
+    pub async fn anomaly_forest(&self, name: &str) -> Result<Option<ForestSummary>, ModelError> {
+        let anomaly_detector = self.anomaly_detector(name)?;
+        let detector = anomaly_detector.read().unwrap();
+        Ok(detector.forest().map(|forest| forest.summary()))
+    }
+
+    // Falls back to baseline scoring, a persisted forest is left on disk
+    pub async fn clear_anomaly_forest(&self, name: &str) -> Result<bool, ModelError> {
+        let had_forest = {
+            let anomaly_detector = self.anomaly_detector(name)?;
+            let mut detector = anomaly_detector.write().unwrap();
+            let had_forest = detector.forest().is_some();
+            detector.set_forest(None);
+            had_forest
+        };
+        if had_forest {
+            self.publish(ModelType::AnomalyDetection, name, ModelChange::ForestCleared, serde_json::Value::Null);
+        }
+        Ok(had_forest)
+    }
+
+    pub async fn anomaly_thresholds(&self, name: &str) -> Result<AnomalyThresholdConfig, ModelError> {
+        let anomaly_detector = self.anomaly_detector(name)?;
+        let detector = anomaly_detector.read().unwrap();
+        Ok(detector.threshold_config())
+    }
+
+    pub async fn update_anomaly_threshold(
         &self,
-        input: ObjectDetectionInput,
-    ) -> Result<ObjectDetectionOutput> {
-        let detector = self.object_detector.read().await;
-        Ok(detector.detect(&input))
+        name: &str,
+        sensor_type: Option<&str>,
+        threshold: f32,
+    ) -> Result<AnomalyThresholdConfig> {
+        let config = {
+            let anomaly_detector = self.anomaly_detector(name)?;
+            let mut detector = anomaly_detector.write().unwrap();
+            detector.update_threshold(sensor_type, threshold)?;
+            detector.threshold_config()
+        };
+        self.publish(ModelType::AnomalyDetection, name, ModelChange::ThresholdChanged, serde_json::to_value(&config)?);
+        Ok(config)
     }
 
-    pub async fn fuse_sensors(
+    // Returns None when the sensor had no override
+    pub async fn clear_anomaly_sensor_threshold(
         &self,
-        input: FusionInput,
-    ) -> Result<FusionOutput> {
-        let fusion = self.sensor_fusion.read().await;
-        Ok(fusion.fuse(&input))
+        name: &str,
+        sensor_type: &str,
+    ) -> Result<Option<AnomalyThresholdConfig>> {
+        let config = {
+            let anomaly_detector = self.anomaly_detector(name)?;
+            let mut detector = anomaly_detector.write().unwrap();
+            if !detector.clear_sensor_threshold(sensor_type) {
+                return Ok(None);
+            }
+            detector.threshold_config()
+        };
+        self.publish(ModelType::AnomalyDetection, name, ModelChange::ThresholdChanged, serde_json::to_value(&config)?);
+        Ok(Some(config))
     }
+}
 
-    pub async fn update_anomaly_threshold(&self, threshold: f32) -> Result<()> {
-        let mut detector = self.anomaly_detector.write().await;
-        detector.update_threshold(threshold);
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    async fn engine(seed: Option<u64>) -> MLEngine {
+        MLEngine::new(EngineOptions { seed, ..Default::default() }, ModelVersions::default()).await
+    }
+
+    fn run(engine: &MLEngine, kind: ModelType, input: &Value, seed: Option<u64>) -> Value {
+        engine
+            .infer(kind.default_name(), Payload::Json(input.clone()), seed, None)
+            .unwrap()
+            .output
+            .to_json()
+            .unwrap()
     }
-}
\ No newline at end of file
+
+    fn stateless_inputs() -> Vec<(ModelType, Value)> {
+        vec![
+            (ModelType::ObjectDetection, json!({"frame_id": "f", "timestamp": 0, "simulate_complex": true})),
+            (ModelType::SensorFusion, json!({"sensor_data": {"camera": true, "lidar": false, "radar": true}, "timestamp": 0})),
+            (
+                ModelType::TrajectoryPrediction,
+                json!({"history": [{"x": 0.0, "y": 0.0, "timestamp": 0}, {"x": 1.0, "y": 2.0, "timestamp": 100}, {"x": 2.5, "y": 3.0, "timestamp": 200}]}),
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn server_seed_repeats_every_prediction() {
+        let engine = engine(Some(5)).await;
+        for (kind, input) in stateless_inputs() {
+            assert_eq!(run(&engine, kind, &input, None), run(&engine, kind, &input, None), "{:?}", kind);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_seed_repeats_a_prediction_and_another_seed_changes_it() {
+        let engine = engine(None).await;
+        let input = json!({"frame_id": "f", "timestamp": 0, "simulate_complex": true});
+        let first = run(&engine, ModelType::ObjectDetection, &input, Some(9));
+        assert_eq!(first, run(&engine, ModelType::ObjectDetection, &input, Some(9)));
+        assert_ne!(first, run(&engine, ModelType::ObjectDetection, &input, Some(10)));
+    }
+
+    #[tokio::test]
+    async fn anomaly_management_targets_the_named_model() {
+        let engine = engine(None).await;
+        let config = ModelConfig {
+            name: "anomaly-strict".to_string(),
+            kind: ModelType::AnomalyDetection,
+            params: Default::default(),
+            enabled: true,
+            artifact: None,
+        };
+        engine.register_model(config).unwrap();
+        let mut updates = engine.subscribe_updates();
+
+        engine.update_anomaly_threshold("anomaly-strict", None, 1.5).await.unwrap();
+        let update = updates.try_recv().unwrap();
+        assert_eq!((update.model.as_str(), update.change), ("anomaly-strict", ModelChange::ThresholdChanged));
+        assert_eq!(engine.anomaly_thresholds("anomaly-strict").await.unwrap().thresholds.global, 1.5);
+        let default = engine.anomaly_thresholds(ModelType::AnomalyDetection.default_name()).await.unwrap();
+        assert_ne!(default.thresholds.global, 1.5);
+
+        assert!(engine.anomaly_thresholds(ModelType::SensorFusion.default_name()).await.is_err());
+    }
+
+    // Stateful models repeat for the same sequence of requests, not for one request alone
+    #[tokio::test]
+    async fn stateful_models_repeat_for_the_same_sequence() {
+        let readings = |value: f32| json!({"sensor_readings": [{"sensor_type": "t", "values": [value], "timestamp": 0}]});
+        let frame = |timestamp: i64| json!({"frame_id": "f", "timestamp": timestamp, "stream_id": "cam"});
+        // Past the baseline's warm-up, so the last reading is actually scored
+        let sequence: Vec<(ModelType, Value)> = (0..12)
+            .map(|i| 10.0 + (i % 3) as f32 * 0.5)
+            .chain([30.0])
+            .map(|value| (ModelType::AnomalyDetection, readings(value)))
+            .chain((0..3).map(|i| (ModelType::ObjectDetection, frame(i * 100))))
+            .collect();
+
+        let (first, second) = (engine(Some(5)).await, engine(Some(5)).await);
+        for (kind, input) in &sequence {
+            assert_eq!(run(&first, *kind, input, None), run(&second, *kind, input, None), "{:?} {}", kind, input);
+        }
+
+        // The same reading scores differently once the baseline has seen others
+        let fresh = engine(Some(5)).await;
+        let last = &sequence[12].1;
+        assert_ne!(run(&fresh, ModelType::AnomalyDetection, last, None), run(&first, ModelType::AnomalyDetection, last, None));
+    }
+}
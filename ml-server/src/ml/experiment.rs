@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::models::ModelError;
+
+use super::{
+    model::{fnv1a, Payload},
+    registry::ModelVersion,
+};
+
+// Share of a model's traffic sent to its candidate version. Sticky splits hash the client
+// id instead of rolling per request, so a client stays on one version, and clients already
+// on the candidate stay there as the percentage grows.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrafficSplit {
+    pub percent: f64,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+impl TrafficSplit {
+    pub fn validate(&self) -> Result<(), ModelError> {
+        if !(self.percent.is_finite() && (0.0..=100.0).contains(&self.percent)) {
+            return Err(ModelError::InvalidInput(format!(
+                "percent must be between 0 and 100, got {}",
+                self.percent
+            )));
+        }
+        Ok(())
+    }
+
+    // Requests without a client id are split at random even on a sticky split. With a seed
+    // the seed and input pick instead, so a seeded request always routes the same way.
+    pub fn picks_candidate(&self, model: &str, client: Option<&str>, seeded: Option<(u64, &Payload)>) -> bool {
+        let bucket = match (client.filter(|_| self.sticky), seeded) {
+            (Some(client), _) => bucket(format!("{}/{}", model, client).as_bytes()),
+            (None, Some((seed, input))) => bucket(format!("{}/{}/{}", model, seed, input.fingerprint()).as_bytes()),
+            (None, None) => rand::random::<f64>() * 100.0,
+        };
+        bucket < self.percent
+    }
+}
+
+// Percentile in [0, 100) with two decimals
+fn bucket(key: &[u8]) -> f64 {
+    (fnv1a(key) % 10_000) as f64 / 100.0
+}
+
+// Body of a request starting an experiment
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentConfig {
+    // Defaults to the model's own artifact
+    #[serde(default)]
+    pub artifact: Option<PathBuf>,
+    pub percent: f64,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+impl ExperimentConfig {
+    pub fn split(&self) -> TrafficSplit {
+        TrafficSplit {
+            percent: self.percent,
+            sticky: self.sticky,
+        }
+    }
+}
+
+pub struct Experiment {
+    pub candidate: Arc<ModelVersion>,
+    pub split: TrafficSplit,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Experiment {
+    pub fn info(&self) -> ExperimentInfo {
+        ExperimentInfo {
+            candidate_version: self.candidate.version.clone(),
+            percent: self.split.percent,
+            sticky: self.split.sticky,
+            started_at: self.started_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExperimentInfo {
+    pub candidate_version: String,
+    pub percent: f64,
+    pub sticky: bool,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Running mean and spread, no samples kept
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Summary {
+    pub count: u64,
+    pub mean: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    #[serde(skip)]
+    m2: f64,
+}
+
+impl Summary {
+    pub fn add(&mut self, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        // Welford's update
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+        self.std_dev = (self.m2 / self.count as f64).sqrt();
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VersionStats {
+    pub requests: u64,
+    pub errors: u64,
+    pub latency_ms: Summary,
+    // Top-level output fields, only sampled while an experiment runs. Booleans count as
+    // 0 or 1 so their mean is a rate, arrays by their length.
+    pub outputs: BTreeMap<String, Summary>,
+}
+
+impl VersionStats {
+    pub fn record(&mut self, latency_ms: f64, output: Option<&serde_json::Value>) {
+        self.requests += 1;
+        self.latency_ms.add(latency_ms);
+        let Some(fields) = output.and_then(|output| output.as_object()) else {
+            return;
+        };
+        for (field, value) in fields {
+            let value = match value {
+                serde_json::Value::Number(n) => n.as_f64(),
+                serde_json::Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+                serde_json::Value::Array(items) => Some(items.len() as f64),
+                _ => None,
+            };
+            if let Some(value) = value {
+                self.outputs.entry(field.clone()).or_default().add(value);
+            }
+        }
+    }
+
+    pub fn record_error(&mut self) {
+        self.requests += 1;
+        self.errors += 1;
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelStats {
+    pub name: String,
+    pub active_version: String,
+    pub experiment: Option<ExperimentInfo>,
+    pub versions: BTreeMap<String, VersionStats>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(percent: f64, sticky: bool) -> TrafficSplit {
+        TrafficSplit { percent, sticky }
+    }
+
+    fn clients() -> Vec<String> {
+        (0..200).map(|i| format!("client-{}", i)).collect()
+    }
+
+    #[test]
+    fn sticky_clients_stay_on_their_version() {
+        let split = split(50.0, true);
+        let picks: Vec<bool> = clients()
+            .iter()
+            .map(|client| split.picks_candidate("m", Some(client), None))
+            .collect();
+        for _ in 0..3 {
+            let again: Vec<bool> = clients()
+                .iter()
+                .map(|client| split.picks_candidate("m", Some(client), None))
+                .collect();
+            assert_eq!(again, picks);
+        }
+        // Roughly half of them, not all on one side
+        let on_candidate = picks.iter().filter(|&&pick| pick).count();
+        assert!((60..140).contains(&on_candidate), "{}", on_candidate);
+    }
+
+    #[test]
+    fn sticky_clients_stay_on_the_candidate_as_it_grows() {
+        for client in clients() {
+            if split(20.0, true).picks_candidate("m", Some(&client), None) {
+                assert!(split(60.0, true).picks_candidate("m", Some(&client), None), "{}", client);
+            }
+        }
+    }
+
+    #[test]
+    fn sticky_buckets_differ_between_models() {
+        let split = split(50.0, true);
+        let routes = |model: &str| -> Vec<bool> {
+            clients().iter().map(|client| split.picks_candidate(model, Some(client), None)).collect()
+        };
+        assert_ne!(routes("a"), routes("b"));
+    }
+
+    #[test]
+    fn seeded_requests_route_the_same_way() {
+        let split = split(50.0, false);
+        let inputs: Vec<Payload> = (0..50).map(|i| Payload::Json(serde_json::json!({ "x": i }))).collect();
+        let routes = |seed: u64| -> Vec<bool> {
+            inputs.iter().map(|input| split.picks_candidate("m", None, Some((seed, input)))).collect()
+        };
+        assert_eq!(routes(1), routes(1));
+        assert_ne!(routes(1), routes(2));
+        // A client id only pins requests on a sticky split
+        let input = &inputs[0];
+        let seeded = split.picks_candidate("m", None, Some((1, input)));
+        for client in clients() {
+            assert_eq!(split.picks_candidate("m", Some(&client), Some((1, input))), seeded);
+        }
+    }
+
+    #[test]
+    fn percent_bounds_route_everything_or_nothing() {
+        for client in clients() {
+            assert!(!split(0.0, true).picks_candidate("m", Some(&client), None));
+            assert!(split(100.0, true).picks_candidate("m", Some(&client), None));
+        }
+        for _ in 0..200 {
+            assert!(!split(0.0, false).picks_candidate("m", None, None));
+            assert!(split(100.0, false).picks_candidate("m", None, None));
+        }
+    }
+
+    #[test]
+    fn a_client_is_picked_once_the_percent_passes_its_bucket() {
+        let client = "client-7";
+        let bucket = bucket(format!("m/{}", client).as_bytes());
+        assert!((0.0..100.0).contains(&bucket));
+        assert!(!split(bucket, true).picks_candidate("m", Some(client), None));
+        assert!(split(bucket + 0.01, true).picks_candidate("m", Some(client), None));
+    }
+
+    #[test]
+    fn percent_outside_0_to_100_is_rejected() {
+        assert!(split(0.0, false).validate().is_ok());
+        assert!(split(100.0, false).validate().is_ok());
+        for percent in [-0.01, 100.01, f64::NAN, f64::INFINITY] {
+            assert!(split(percent, false).validate().is_err(), "{}", percent);
+        }
+    }
+}
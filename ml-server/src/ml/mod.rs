@@ -1 +1,7 @@
-pub mod engine;
\ No newline at end of file
+pub mod artifact;
+pub mod engine;
+pub mod experiment;
+pub mod model;
+pub mod pipeline;
+pub mod registry;
+pub mod shadow;
@@ -0,0 +1,140 @@
+use anyhow::Result;
+use rand::{rngs::StdRng, SeedableRng};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Serialize};
+use std::any::Any;
+use std::sync::{Arc, RwLock};
+
+use crate::models::{binary::WireEncoding, ModelError};
+
+// Implemented once per model, the registry takes care of decoding, seeding and encoding
+pub trait Model: Send + Sync + 'static {
+    type Input: DeserializeOwned + Serialize + JsonSchema + Send;
+    type Output: Serialize + Send + 'static;
+    // What the model is built from, saved in artifacts
+    type Params: Serialize;
+
+    fn name(&self) -> &'static str;
+    fn version(&self) -> String;
+    // The current parameters, enough to rebuild the model as it is now
+    fn params(&self) -> Self::Params;
+    // Deterministic models can ignore the rng
+    fn predict(&self, input: &Self::Input, rng: &mut StdRng) -> Result<Self::Output>;
+}
+
+// For models whose parameters change at runtime
+impl<M: Model> Model for RwLock<M> {
+    type Input = M::Input;
+    type Output = M::Output;
+    type Params = M::Params;
+
+    fn name(&self) -> &'static str {
+        self.read().unwrap().name()
+    }
+
+    fn version(&self) -> String {
+        self.read().unwrap().version()
+    }
+
+    fn params(&self) -> Self::Params {
+        self.read().unwrap().params()
+    }
+
+    fn predict(&self, input: &Self::Input, rng: &mut StdRng) -> Result<Self::Output> {
+        self.read().unwrap().predict(input, rng)
+    }
+}
+
+// Model input as it arrived, decoded by the model that ends up handling it
+#[derive(Clone)]
+pub enum Payload {
+    Json(serde_json::Value),
+    Encoded(WireEncoding, Vec<u8>),
+}
+
+impl Payload {
+    // Stable across runs, JSON object keys are kept sorted
+    pub fn fingerprint(&self) -> u64 {
+        match self {
+            Payload::Json(data) => fnv1a(&serde_json::to_vec(data).unwrap_or_default()),
+            Payload::Encoded(_, data) => fnv1a(data),
+        }
+    }
+}
+
+// Model output, serialized in whatever form the transport needs
+pub trait Prediction: Send {
+    fn to_json(&self) -> serde_json::Result<serde_json::Value>;
+    fn encode(&self, encoding: WireEncoding) -> Result<Vec<u8>>;
+}
+
+impl<T: Serialize + Send> Prediction for T {
+    fn to_json(&self) -> serde_json::Result<serde_json::Value> {
+        serde_json::to_value(self)
+    }
+
+    fn encode(&self, encoding: WireEncoding) -> Result<Vec<u8>> {
+        encoding.encode(self)
+    }
+}
+
+// Object-safe view of a Model so differently typed models can share one registry
+pub trait DynModel: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn version(&self) -> String;
+    fn predict(&self, input: Payload, seed: Option<u64>) -> Result<Box<dyn Prediction>>;
+    fn input_schema(&self) -> serde_json::Value;
+    fn params(&self) -> serde_json::Result<serde_json::Value>;
+    // Recovers the concrete model, for management calls the trait does not cover
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+}
+
+impl<M: Model> DynModel for M {
+    fn name(&self) -> &'static str {
+        Model::name(self)
+    }
+
+    fn version(&self) -> String {
+        Model::version(self)
+    }
+
+    fn predict(&self, input: Payload, seed: Option<u64>) -> Result<Box<dyn Prediction>> {
+        let input: M::Input = match input {
+            Payload::Json(data) => serde_json::from_value(data).map_err(|e| ModelError::InvalidInput(e.to_string()))?,
+            Payload::Encoded(encoding, data) => encoding
+                .decode(&data)
+                .map_err(|e| ModelError::InvalidInput(e.to_string()))?,
+        };
+        let mut rng = rng_for(seed, &input)?;
+        Ok(Box::new(Model::predict(self, &input, &mut rng)?))
+    }
+
+    fn input_schema(&self) -> serde_json::Value {
+        serde_json::to_value(schemars::schema_for!(M::Input)).unwrap_or_default()
+    }
+
+    fn params(&self) -> serde_json::Result<serde_json::Value> {
+        serde_json::to_value(Model::params(self))
+    }
+
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+}
+
+// With a seed the input is mixed in, so identical inputs reproduce while different
+// inputs still vary. Inputs hold no HashMaps, so their bincode encoding is canonical
+// and image bytes are hashed as they are.
+fn rng_for<T: Serialize>(seed: Option<u64>, input: &T) -> Result<StdRng> {
+    Ok(match seed {
+        Some(seed) => StdRng::seed_from_u64(seed ^ fnv1a(&bincode::serialize(input)?)),
+        None => StdRng::from_entropy(),
+    })
+}
+
+// Stable across builds and platforms, unlike std's DefaultHasher
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
@@ -0,0 +1,205 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::models::{
+    objects::ObjectDetectionOutput,
+    trajectory::{MotionModel, TrajectoryPoint},
+};
+
+// Older points are dropped, the trajectory model only needs the recent motion
+const MAX_HISTORY_POINTS: usize = 32;
+// Streams not seen for this long are dropped once the stream limit is reached
+const MAX_STREAMS: usize = 1024;
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Body of a pipeline request: one frame of a stream, detected and tracked, then a
+// trajectory predicted for every object tracked in it
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineRequest {
+    // Object detection input, stream_id is required
+    pub frame: serde_json::Value,
+    // Defaults to the default trajectory model
+    #[serde(default)]
+    pub trajectory_model: Option<String>,
+    #[serde(default)]
+    pub trajectory: TrajectoryOptions,
+}
+
+// Everything a trajectory request takes besides the history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrajectoryOptions {
+    pub prediction_horizon: usize,
+    pub prediction_horizon_ms: Option<i64>,
+    pub output_interval_ms: Option<i64>,
+    pub motion_model: MotionModel,
+}
+
+impl Default for TrajectoryOptions {
+    fn default() -> Self {
+        Self {
+            prediction_horizon: 10,
+            prediction_horizon_ms: None,
+            output_interval_ms: None,
+            motion_model: MotionModel::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PipelineResponse {
+    pub model: String,
+    pub version: String,
+    pub detection: ObjectDetectionOutput,
+    // One per tracked object in the frame, in detection order
+    pub trajectories: Vec<TrackTrajectory>,
+    pub latency_ms: f64,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// Neither prediction nor error is set until the track has two points
+#[derive(Debug, Serialize)]
+pub struct TrackTrajectory {
+    pub track_id: u64,
+    pub history_points: usize,
+    pub prediction: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+struct StreamHistory {
+    tracks: HashMap<u64, VecDeque<TrajectoryPoint>>,
+    last_active: Instant,
+}
+
+// Box centers of every track seen through the pipeline, per detector version and stream.
+// Each version tracks with ids of its own, and an experiment can split one stream's
+// frames between two versions.
+#[derive(Default)]
+pub struct TrackHistories {
+    streams: DashMap<String, StreamHistory>,
+}
+
+impl TrackHistories {
+    // Adds this frame's tracked objects and returns their histories, in detection order
+    pub fn record(
+        &self,
+        model: &str,
+        version: &str,
+        stream: &str,
+        timestamp: i64,
+        detection: &ObjectDetectionOutput,
+    ) -> Vec<(u64, Vec<TrajectoryPoint>)> {
+        let key = format!("{}/{}/{}", model, version, stream);
+        if !self.streams.contains_key(&key) && self.streams.len() >= MAX_STREAMS {
+            self.streams.retain(|_, history| history.last_active.elapsed() < STREAM_IDLE_TIMEOUT);
+        }
+        let mut history = self.streams.entry(key).or_insert_with(|| StreamHistory {
+            tracks: HashMap::new(),
+            last_active: Instant::now(),
+        });
+        history.last_active = Instant::now();
+        for track_id in &detection.retired_tracks {
+            history.tracks.remove(track_id);
+        }
+
+        let mut histories = Vec::new();
+        for object in &detection.objects {
+            let Some(track) = &object.track else {
+                continue;
+            };
+            let points = history.tracks.entry(track.track_id).or_default();
+            // A new track can reuse an id, e.g. after the detector is reloaded
+            if track.age == 1 || points.back().is_some_and(|point| point.timestamp >= timestamp) {
+                points.clear();
+            }
+            let (x, y) = object.bounding_box.center();
+            points.push_back(TrajectoryPoint { x, y, timestamp });
+            if points.len() > MAX_HISTORY_POINTS {
+                points.pop_front();
+            }
+            histories.push((track.track_id, points.iter().cloned().collect()));
+        }
+        histories
+    }
+
+    pub fn forget(&self, model: &str) {
+        let prefix = format!("{}/", model);
+        self.streams.retain(|key, _| !key.starts_with(&prefix));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{
+        objects::{BoundingBox, DetectedObject},
+        tracking::TrackInfo,
+    };
+
+    // Tracked objects as (track id, age, box x)
+    fn frame(objects: &[(u64, u32, f32)], retired_tracks: Vec<u64>) -> ObjectDetectionOutput {
+        let objects = objects
+            .iter()
+            .map(|&(track_id, age, x)| DetectedObject {
+                id: format!("track_{}", track_id),
+                class_name: "car".to_string(),
+                confidence: 0.9,
+                bounding_box: BoundingBox { x, y: 0.0, width: 10.0, height: 10.0 },
+                track: Some(TrackInfo { track_id, age, age_ms: 0, hits: age, velocity_x: 0.0, velocity_y: 0.0 }),
+            })
+            .collect();
+        ObjectDetectionOutput { frame_id: String::new(), objects, retired_tracks }
+    }
+
+    fn lengths(histories: &[(u64, Vec<TrajectoryPoint>)]) -> Vec<(u64, usize)> {
+        histories.iter().map(|(track_id, points)| (*track_id, points.len())).collect()
+    }
+
+    #[test]
+    fn histories_accumulate_box_centers_per_track() {
+        let histories = TrackHistories::default();
+        histories.record("d", "1", "s", 0, &frame(&[(1, 1, 0.0)], vec![]));
+        histories.record("d", "1", "s", 100, &frame(&[(1, 2, 10.0), (2, 1, 50.0)], vec![]));
+        let recorded = histories.record("d", "1", "s", 200, &frame(&[(2, 2, 60.0), (1, 3, 20.0)], vec![]));
+        assert_eq!(lengths(&recorded), vec![(2, 2), (1, 3)]);
+        let xs: Vec<f32> = recorded[1].1.iter().map(|point| point.x).collect();
+        assert_eq!(xs, vec![5.0, 15.0, 25.0]);
+    }
+
+    #[test]
+    fn retired_and_restarted_tracks_start_over() {
+        let histories = TrackHistories::default();
+        histories.record("d", "1", "s", 0, &frame(&[(1, 1, 0.0), (2, 1, 50.0)], vec![]));
+        histories.record("d", "1", "s", 100, &frame(&[(1, 2, 10.0), (2, 2, 60.0)], vec![]));
+        // Track 1 is retired, track 2's id comes back as a new track
+        let recorded = histories.record("d", "1", "s", 200, &frame(&[(2, 1, 90.0)], vec![1]));
+        assert_eq!(lengths(&recorded), vec![(2, 1)]);
+        let recorded = histories.record("d", "1", "s", 300, &frame(&[(1, 1, 0.0)], vec![]));
+        assert_eq!(lengths(&recorded), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn versions_and_streams_keep_separate_histories() {
+        let histories = TrackHistories::default();
+        histories.record("d", "1", "s", 0, &frame(&[(1, 1, 0.0)], vec![]));
+        // The same track id from the other side of an experiment is another object
+        histories.record("d", "2", "s", 100, &frame(&[(1, 1, 500.0)], vec![]));
+        histories.record("d", "1", "other", 100, &frame(&[(1, 1, 900.0)], vec![]));
+        let recorded = histories.record("d", "1", "s", 200, &frame(&[(1, 2, 10.0)], vec![]));
+        let xs: Vec<f32> = recorded[0].1.iter().map(|point| point.x).collect();
+        assert_eq!(xs, vec![5.0, 15.0]);
+    }
+
+    #[test]
+    fn forget_drops_every_version_of_a_model() {
+        let histories = TrackHistories::default();
+        histories.record("d", "1", "s", 0, &frame(&[(1, 1, 0.0)], vec![]));
+        histories.record("d", "2", "s", 0, &frame(&[(1, 1, 0.0)], vec![]));
+        histories.record("d2", "1", "s", 0, &frame(&[(1, 1, 0.0)], vec![]));
+        histories.forget("d");
+        assert_eq!(histories.streams.len(), 1);
+    }
+}
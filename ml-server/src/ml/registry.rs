@@ -0,0 +1,535 @@
+use arc_swap::{ArcSwap, ArcSwapOption};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::models::{
+    trajectory::TrajectoryPredictor,
+    anomaly::AnomalyDetector,
+    objects::ObjectDetector,
+    fusion::SensorFusion,
+    ModelChange, ModelError, ModelType,
+};
+
+use super::{
+    artifact::{resolve_path, ModelArtifact},
+    experiment::{Experiment, ExperimentInfo, TrafficSplit, VersionStats},
+    model::{DynModel, Payload},
+    shadow::Shadow,
+};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelStatus {
+    Ready,
+    Disabled,
+}
+
+// One entry of the models config file, also the body of a registration request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelConfig {
+    pub name: String,
+    pub kind: ModelType,
+    // Kind specific, e.g. {"threshold": 2.0} for anomaly_detection
+    #[serde(default)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    // Artifact file to load instead of `params`, reloads re-read it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact: Option<PathBuf>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModelsConfig {
+    pub models: Vec<ModelConfig>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelInfo {
+    pub name: String,
+    pub kind: ModelType,
+    pub algorithm: &'static str,
+    pub version: String,
+    pub status: ModelStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact: Option<PathBuf>,
+    // What a rollback would restore, newest last
+    pub previous_versions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<ExperimentInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow_version: Option<String>,
+    pub input_schema: serde_json::Value,
+}
+
+// Entry of AppState::model_versions, one per version a model has served
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionRecord {
+    pub version: String,
+    pub change: ModelChange,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+// Version history by model name, shared by the engine and the REST handlers
+pub type ModelVersions = Arc<RwLock<HashMap<String, Vec<VersionRecord>>>>;
+
+const MAX_ROLLBACK_DEPTH: usize = 5;
+
+// One loaded set of params. Requests hold on to the version they started with, so a
+// swap never changes a model under a running prediction.
+pub struct ModelVersion {
+    pub version: String,
+    pub model: Arc<dyn DynModel>,
+    // Artifact it was loaded from, None for inline params
+    pub source: Option<PathBuf>,
+}
+
+pub struct RegisteredModel {
+    pub name: String,
+    pub kind: ModelType,
+    pub status: ModelStatus,
+    pub artifact: Option<PathBuf>,
+    // Where paths in requests and params are resolved
+    model_dir: Option<PathBuf>,
+    active: ArcSwap<ModelVersion>,
+    // Versions replaced by reloads, newest last
+    previous: Mutex<Vec<Arc<ModelVersion>>>,
+    // Candidate version taking a share of the traffic, if any
+    experiment: ArcSwapOption<Experiment>,
+    // Candidate mirroring every request without serving any
+    shadow: ArcSwapOption<Shadow>,
+    // By version, reset when an experiment starts
+    stats: Mutex<BTreeMap<String, VersionStats>>,
+}
+
+impl RegisteredModel {
+    // The config's artifact path is used as it is, see resolve_path
+    pub fn from_config(config: ModelConfig, model_dir: Option<&Path>) -> Result<Self, ModelError> {
+        if config.name.is_empty()
+            || !config.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(ModelError::InvalidInput(format!(
+                "model name {:?} must be non-empty and only use letters, digits, '-' and '_'",
+                config.name
+            )));
+        }
+
+        let active = match &config.artifact {
+            Some(path) => load_version(&config.name, config.kind, path, model_dir)?,
+            None => {
+                let model = build_model(&config.name, config.kind, config.params, model_dir)?;
+                ModelVersion { version: model.version(), model, source: None }
+            }
+        };
+
+        Ok(Self {
+            name: config.name,
+            kind: config.kind,
+            status: if config.enabled { ModelStatus::Ready } else { ModelStatus::Disabled },
+            artifact: config.artifact,
+            model_dir: model_dir.map(Path::to_path_buf),
+            active: ArcSwap::from_pointee(active),
+            previous: Mutex::new(Vec::new()),
+            experiment: ArcSwapOption::empty(),
+            shadow: ArcSwapOption::empty(),
+            stats: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    // For artifacts found in the model directory, which know their own kind
+    pub fn from_artifact(name: String, path: PathBuf, model_dir: Option<&Path>) -> Result<Self, ModelError> {
+        let kind = load_artifact(&path)?.kind;
+        Self::from_config(ModelConfig {
+            name,
+            kind,
+            params: Default::default(),
+            enabled: true,
+            artifact: Some(path),
+        }, model_dir)
+    }
+
+    pub fn active(&self) -> Arc<ModelVersion> {
+        self.active.load_full()
+    }
+
+    // The version to serve a request with, and whether an experiment picked it
+    pub fn route(&self, client: Option<&str>, seeded: Option<(u64, &Payload)>) -> (Arc<ModelVersion>, bool) {
+        match self.experiment.load_full() {
+            Some(experiment) if experiment.split.picks_candidate(&self.name, client, seeded) => {
+                (experiment.candidate.clone(), true)
+            }
+            Some(_) => (self.active(), true),
+            None => (self.active(), false),
+        }
+    }
+
+    // Builds the version in an artifact file without swapping it in, the model's own
+    // artifact unless another is given
+    pub fn load_artifact(&self, path: Option<&Path>) -> Result<ModelVersion, ModelError> {
+        let path = match path {
+            Some(path) => resolve_path(self.model_dir.as_deref(), path)?,
+            None => self.artifact.clone().ok_or_else(|| {
+                ModelError::InvalidInput(format!("model {} has no artifact to load from", self.name))
+            })?,
+        };
+        load_version(&self.name, self.kind, &path, self.model_dir.as_deref())
+    }
+
+    // Makes `version` active, keeping the one it replaces for rollback. Returns the shadow
+    // it stopped if that was shadowing `version`.
+    pub fn swap(&self, version: Arc<ModelVersion>) -> Option<Arc<Shadow>> {
+        let mut previous = self.previous.lock().unwrap();
+        previous.push(self.active.swap(version.clone()));
+        if previous.len() > MAX_ROLLBACK_DEPTH {
+            previous.remove(0);
+        }
+        self.stop_shadow_of(&version)
+    }
+
+    // Restores the version the last swap replaced, dropping the active one. Like swap, stops
+    // a shadow of the restored version.
+    pub fn rollback(&self) -> Result<(Arc<ModelVersion>, Option<Arc<Shadow>>), ModelError> {
+        let mut previous = self.previous.lock().unwrap();
+        let restored = previous.pop().ok_or_else(|| {
+            ModelError::InvalidInput(format!("model {} has no previous version to roll back to", self.name))
+        })?;
+        self.active.store(restored.clone());
+        let stopped = self.stop_shadow_of(&restored);
+        Ok((restored, stopped))
+    }
+
+    // Replaces any running experiment, starting its stats from scratch
+    pub fn start_experiment(&self, candidate: Arc<ModelVersion>, split: TrafficSplit) -> Result<(), ModelError> {
+        split.validate()?;
+        if candidate.version == self.active().version {
+            return Err(ModelError::InvalidInput(format!(
+                "candidate version {} is already active on {}",
+                candidate.version, self.name
+            )));
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.clear();
+        self.experiment.store(Some(Arc::new(Experiment {
+            candidate,
+            split,
+            started_at: chrono::Utc::now(),
+        })));
+        Ok(())
+    }
+
+    // Keeps the candidate and its stats, for gradual rollouts
+    pub fn update_split(&self, split: TrafficSplit) -> Result<ExperimentInfo, ModelError> {
+        split.validate()?;
+        let experiment = self.running_experiment()?;
+        let updated = Experiment {
+            candidate: experiment.candidate.clone(),
+            split,
+            started_at: experiment.started_at,
+        };
+        let info = updated.info();
+        self.experiment.store(Some(Arc::new(updated)));
+        Ok(info)
+    }
+
+    pub fn stop_experiment(&self) -> Result<Arc<Experiment>, ModelError> {
+        self.experiment.swap(None).ok_or_else(|| self.no_experiment())
+    }
+
+    // Ends the experiment with the candidate as the active version, rollback restores the old one
+    pub fn promote(&self) -> Result<(Arc<ModelVersion>, Option<Arc<Shadow>>), ModelError> {
+        let experiment = self.stop_experiment()?;
+        let stopped = self.swap(experiment.candidate.clone());
+        Ok((experiment.candidate.clone(), stopped))
+    }
+
+    pub fn experiment(&self) -> Option<ExperimentInfo> {
+        self.experiment.load().as_ref().map(|experiment| experiment.info())
+    }
+
+    fn running_experiment(&self) -> Result<Arc<Experiment>, ModelError> {
+        self.experiment.load_full().ok_or_else(|| self.no_experiment())
+    }
+
+    fn no_experiment(&self) -> ModelError {
+        ModelError::InvalidInput(format!("model {} has no experiment running", self.name))
+    }
+
+    // Replaces any running shadow, its metrics go with it
+    pub fn start_shadow(&self, candidate: Arc<ModelVersion>) -> Result<Arc<Shadow>, ModelError> {
+        if candidate.version == self.active().version {
+            return Err(ModelError::InvalidInput(format!(
+                "shadow version {} is already active on {}",
+                candidate.version, self.name
+            )));
+        }
+        let shadow = Arc::new(Shadow::new(candidate));
+        self.shadow.store(Some(shadow.clone()));
+        Ok(shadow)
+    }
+
+    pub fn stop_shadow(&self) -> Result<Arc<Shadow>, ModelError> {
+        self.shadow.swap(None).ok_or_else(|| self.no_shadow())
+    }
+
+    // A version that became active has nothing left to be compared with
+    fn stop_shadow_of(&self, version: &ModelVersion) -> Option<Arc<Shadow>> {
+        let shadow = self.shadow().filter(|shadow| shadow.candidate.version == version.version)?;
+        // Unless a new shadow replaced it in the meantime
+        let previous = self.shadow.compare_and_swap(&Some(shadow.clone()), None);
+        previous.as_ref().is_some_and(|previous| Arc::ptr_eq(previous, &shadow)).then_some(shadow)
+    }
+
+    pub fn shadow(&self) -> Option<Arc<Shadow>> {
+        self.shadow.load_full()
+    }
+
+    // The shadow to compare a request's output with. Only what the active version served
+    // is compared, requests an experiment sent to its candidate are not.
+    pub fn shadow_for(&self, served: &ModelVersion) -> Option<Arc<Shadow>> {
+        self.shadow().filter(|_| served.version == self.active().version)
+    }
+
+    pub fn running_shadow(&self) -> Result<Arc<Shadow>, ModelError> {
+        self.shadow().ok_or_else(|| self.no_shadow())
+    }
+
+    fn no_shadow(&self) -> ModelError {
+        ModelError::InvalidInput(format!("model {} has no shadow running", self.name))
+    }
+
+    pub fn record(&self, version: &str, latency_ms: f64, output: Option<&serde_json::Value>) {
+        self.stats.lock().unwrap().entry(version.to_string()).or_default().record(latency_ms, output);
+    }
+
+    pub fn record_error(&self, version: &str) {
+        self.stats.lock().unwrap().entry(version.to_string()).or_default().record_error();
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, VersionStats> {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn info(&self) -> ModelInfo {
+        let active = self.active();
+        ModelInfo {
+            name: self.name.clone(),
+            kind: self.kind,
+            algorithm: active.model.name(),
+            version: active.version.clone(),
+            status: self.status,
+            artifact: self.artifact.clone(),
+            previous_versions: self.previous.lock().unwrap().iter().map(|v| v.version.clone()).collect(),
+            experiment: self.experiment(),
+            shadow_version: self.shadow().map(|shadow| shadow.candidate.version.clone()),
+            input_schema: active.model.input_schema(),
+        }
+    }
+}
+
+impl ModelVersion {
+    // Snapshot of the model as it is now, runtime changes such as threshold updates included
+    pub fn to_artifact(&self, kind: ModelType, version: String) -> anyhow::Result<ModelArtifact> {
+        let params = match self.model.params()? {
+            serde_json::Value::Object(params) => params,
+            other => anyhow::bail!("{:?} params serialized to {}, expected an object", kind, other),
+        };
+        Ok(ModelArtifact {
+            kind,
+            version,
+            created_at: chrono::Utc::now(),
+            params,
+        })
+    }
+}
+
+fn load_artifact(path: &Path) -> Result<ModelArtifact, ModelError> {
+    ModelArtifact::load(path)
+        .map_err(|e| ModelError::InvalidInput(format!("failed to load artifact {}: {}", path.display(), e)))
+}
+
+fn load_version(name: &str, kind: ModelType, path: &Path, model_dir: Option<&Path>) -> Result<ModelVersion, ModelError> {
+    let artifact = load_artifact(path)?;
+    if artifact.kind != kind {
+        return Err(ModelError::InvalidInput(format!(
+            "artifact {} holds a {:?} model, {} is {:?}",
+            path.display(),
+            artifact.kind,
+            name,
+            kind
+        )));
+    }
+    Ok(ModelVersion {
+        version: artifact.version,
+        model: build_model(name, kind, artifact.params, model_dir)?,
+        source: Some(path.to_path_buf()),
+    })
+}
+
+// The one place that knows how to build each kind of model
+fn build_model(
+    name: &str,
+    kind: ModelType,
+    params: serde_json::Map<String, serde_json::Value>,
+    model_dir: Option<&Path>,
+) -> Result<Arc<dyn DynModel>, ModelError> {
+    let model: Arc<dyn DynModel> = match kind {
+        ModelType::TrajectoryPrediction => Arc::new(TrajectoryPredictor::with_params(parse_params(name, params)?)?),
+        ModelType::AnomalyDetection => Arc::new(RwLock::new(AnomalyDetector::with_params(parse_params(name, params)?)?)),
+        ModelType::ObjectDetection => build_detector(name, params, model_dir)?,
+        ModelType::SensorFusion => Arc::new(SensorFusion::with_params(parse_params(name, params)?)?),
+    };
+    Ok(model)
+}
+
+// Detectors pick a backend with a "backend" param, the simulated one by default
+fn build_detector(
+    name: &str,
+    mut params: serde_json::Map<String, serde_json::Value>,
+    #[cfg_attr(not(feature = "onnx"), allow(unused_variables))] model_dir: Option<&Path>,
+) -> Result<Arc<dyn DynModel>, ModelError> {
+    let backend = match params.remove("backend") {
+        None => "simulated".to_string(),
+        Some(serde_json::Value::String(backend)) => backend,
+        Some(other) => {
+            return Err(ModelError::InvalidInput(format!("invalid params for {}: backend must be a string, got {}", name, other)));
+        }
+    };
+    match backend.as_str() {
+        "simulated" => Ok(Arc::new(ObjectDetector::with_params(parse_params(name, params)?)?)),
+        #[cfg(feature = "onnx")]
+        "onnx" => Ok(Arc::new(crate::models::onnx::OnnxDetector::with_params(parse_params(name, params)?, model_dir)?)),
+        #[cfg(not(feature = "onnx"))]
+        "onnx" => Err(ModelError::InvalidInput(
+            "the onnx backend is not available, the server must be built with --features onnx".to_string(),
+        )),
+        other => Err(ModelError::InvalidInput(format!(
+            "invalid params for {}: unknown detector backend {:?}, expected \"simulated\" or \"onnx\"",
+            name, other
+        ))),
+    }
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(
+    name: &str,
+    params: serde_json::Map<String, serde_json::Value>,
+) -> Result<T, ModelError> {
+    serde_json::from_value(serde_json::Value::Object(params))
+        .map_err(|e| ModelError::InvalidInput(format!("invalid params for {}: {}", name, e)))
+}
+
+#[derive(Default)]
+pub struct ModelRegistry {
+    models: BTreeMap<String, Arc<RegisteredModel>>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the entry it replaced, if any
+    pub fn register(&mut self, model: RegisteredModel) -> Option<Arc<RegisteredModel>> {
+        self.models.insert(model.name.clone(), Arc::new(model))
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<Arc<RegisteredModel>> {
+        self.models.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<RegisteredModel>> {
+        self.models.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.models.contains_key(name)
+    }
+
+    // Sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<RegisteredModel>> {
+        self.models.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model() -> RegisteredModel {
+        RegisteredModel::from_config(ModelConfig {
+            name: "fusion".to_string(),
+            kind: ModelType::SensorFusion,
+            params: Default::default(),
+            enabled: true,
+            artifact: None,
+        }, None).unwrap()
+    }
+
+    // Another version label for the same model
+    fn candidate(model: &RegisteredModel, version: &str) -> Arc<ModelVersion> {
+        Arc::new(ModelVersion {
+            version: version.to_string(),
+            model: model.active().model.clone(),
+            source: None,
+        })
+    }
+
+    fn split(percent: f64) -> TrafficSplit {
+        TrafficSplit { percent, sticky: false }
+    }
+
+    #[test]
+    fn promoting_the_shadowed_version_stops_the_shadow() {
+        let model = model();
+        let next = candidate(&model, "next");
+        model.start_experiment(next.clone(), split(50.0)).unwrap();
+        model.start_shadow(next.clone()).unwrap();
+
+        let (promoted, stopped) = model.promote().unwrap();
+        assert_eq!(promoted.version, "next");
+        assert_eq!(stopped.unwrap().candidate.version, "next");
+        assert!(model.shadow().is_none());
+    }
+
+    #[test]
+    fn promoting_another_version_keeps_the_shadow() {
+        let model = model();
+        model.start_experiment(candidate(&model, "next"), split(50.0)).unwrap();
+        model.start_shadow(candidate(&model, "other")).unwrap();
+
+        let (_, stopped) = model.promote().unwrap();
+        assert!(stopped.is_none());
+        assert_eq!(model.shadow().unwrap().candidate.version, "other");
+    }
+
+    #[test]
+    fn rolling_back_to_the_shadowed_version_stops_the_shadow() {
+        let model = model();
+        let original = model.active();
+        model.swap(candidate(&model, "next"));
+        model.start_shadow(original.clone()).unwrap();
+
+        let (restored, stopped) = model.rollback().unwrap();
+        assert_eq!(restored.version, original.version);
+        assert!(stopped.is_some());
+        assert!(model.shadow().is_none());
+    }
+
+    #[test]
+    fn only_the_active_version_is_compared_with_the_shadow() {
+        let model = model();
+        let next = candidate(&model, "next");
+        model.start_experiment(next.clone(), split(50.0)).unwrap();
+        model.start_shadow(candidate(&model, "other")).unwrap();
+
+        assert!(model.shadow_for(&model.active()).is_some());
+        assert!(model.shadow_for(&next).is_none());
+    }
+}
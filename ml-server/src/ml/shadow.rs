@@ -0,0 +1,252 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+use crate::models::{
+    anomaly::AnomalyDetectionOutput,
+    fusion::FusionOutput,
+    objects::ObjectDetectionOutput,
+    trajectory::TrajectoryPredictionOutput,
+    ModelType,
+};
+
+use super::{
+    experiment::Summary,
+    model::Payload,
+    registry::ModelVersion,
+};
+
+// Body of a request starting a shadow
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowConfig {
+    // Defaults to the model's own artifact
+    #[serde(default)]
+    pub artifact: Option<PathBuf>,
+}
+
+// A candidate version that sees every request the active version serves. Its output is
+// only compared with the active version's, never returned.
+pub struct Shadow {
+    pub candidate: Arc<ModelVersion>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    stats: Mutex<ShadowStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ShadowStats {
+    pub compared: u64,
+    // Outputs equal field for field
+    pub identical: u64,
+    pub shadow_errors: u64,
+    // Skipped because too many shadow predictions were already running
+    pub dropped: u64,
+    pub shadow_latency_ms: Summary,
+    // Kind specific, e.g. displacement_error for trajectories
+    pub divergence: BTreeMap<String, Summary>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShadowReport {
+    pub candidate_version: String,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    pub stats: ShadowStats,
+}
+
+impl Shadow {
+    pub fn new(candidate: Arc<ModelVersion>) -> Self {
+        Self {
+            candidate,
+            started_at: chrono::Utc::now(),
+            stats: Mutex::new(ShadowStats::default()),
+        }
+    }
+
+    // Blocking, runs on the blocking pool with the same input and seed the request had
+    pub fn run(&self, kind: ModelType, input: Payload, seed: Option<u64>, primary: &serde_json::Value) {
+        let start = std::time::Instant::now();
+        let output = self.candidate.model
+            .predict(input, seed)
+            .and_then(|output| Ok(output.to_json()?));
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        let mut stats = self.stats.lock().unwrap();
+        let shadow = match output {
+            Ok(shadow) => shadow,
+            Err(e) => {
+                debug!("Shadow {} failed: {}", self.candidate.version, e);
+                stats.shadow_errors += 1;
+                return;
+            }
+        };
+        stats.compared += 1;
+        stats.shadow_latency_ms.add(latency_ms);
+        if shadow == *primary {
+            stats.identical += 1;
+        }
+        for (metric, value) in divergence(kind, primary, &shadow) {
+            stats.divergence.entry(metric.to_string()).or_default().add(value);
+        }
+    }
+
+    pub fn record_dropped(&self) {
+        self.stats.lock().unwrap().dropped += 1;
+    }
+
+    pub fn report(&self) -> ShadowReport {
+        ShadowReport {
+            candidate_version: self.candidate.version.clone(),
+            started_at: self.started_at,
+            stats: self.stats.lock().unwrap().clone(),
+        }
+    }
+}
+
+// Outputs that do not parse as the kind's output type only count towards `identical`
+fn divergence(kind: ModelType, primary: &serde_json::Value, shadow: &serde_json::Value) -> Vec<(&'static str, f64)> {
+    fn parse<T: serde::de::DeserializeOwned>(a: &serde_json::Value, b: &serde_json::Value) -> Option<(T, T)> {
+        Some((T::deserialize(a).ok()?, T::deserialize(b).ok()?))
+    }
+    let flag = |differs: bool| if differs { 1.0 } else { 0.0 };
+
+    match kind {
+        ModelType::TrajectoryPrediction => {
+            let Some((a, b)) = parse::<TrajectoryPredictionOutput>(primary, shadow) else {
+                return Vec::new();
+            };
+            let errors: Vec<f64> = a.predictions
+                .iter()
+                .zip(&b.predictions)
+                .map(|(a, b)| ((a.x - b.x) as f64).hypot((a.y - b.y) as f64))
+                .collect();
+            let mut metrics = vec![("confidence_delta", (a.confidence - b.confidence).abs() as f64)];
+            if let Some(&last) = errors.last() {
+                metrics.push(("displacement_error", errors.iter().sum::<f64>() / errors.len() as f64));
+                metrics.push(("final_displacement_error", last));
+            }
+            metrics
+        }
+        ModelType::AnomalyDetection => {
+            let Some((a, b)) = parse::<AnomalyDetectionOutput>(primary, shadow) else {
+                return Vec::new();
+            };
+            vec![
+                ("flag_disagreement", flag(a.is_anomaly != b.is_anomaly)),
+                ("score_delta", (a.anomaly_score - b.anomaly_score).abs() as f64),
+            ]
+        }
+        ModelType::ObjectDetection => {
+            let Some((a, b)) = parse::<ObjectDetectionOutput>(primary, shadow) else {
+                return Vec::new();
+            };
+            vec![("count_delta", (a.objects.len() as f64 - b.objects.len() as f64).abs())]
+        }
+        ModelType::SensorFusion => {
+            let Some((a, b)) = parse::<FusionOutput>(primary, shadow) else {
+                return Vec::new();
+            };
+            vec![
+                ("confidence_delta", (a.overall_confidence - b.overall_confidence).abs() as f64),
+                ("quality_disagreement", flag(a.fusion_quality != b.fusion_quality)),
+            ]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn trajectory(points: &[(f32, f32)], confidence: f32) -> Value {
+        let predictions: Vec<Value> = points
+            .iter()
+            .enumerate()
+            .map(|(i, &(x, y))| {
+                json!({
+                    "x": x, "y": y, "timestamp": i as i64 * 100, "std_x": 0.0, "std_y": 0.0,
+                    "uncertainty": {"semi_major": 0.0, "semi_minor": 0.0, "orientation": 0.0},
+                })
+            })
+            .collect();
+        json!({"predictions": predictions, "confidence": confidence, "hypotheses": []})
+    }
+
+    fn anomaly(score: f32, is_anomaly: bool) -> Value {
+        json!({
+            "anomaly_score": score, "is_anomaly": is_anomaly, "threshold": 3.0,
+            "sensor_scores": [], "sensor_thresholds": [], "method": "baseline",
+        })
+    }
+
+    fn objects(count: usize) -> Value {
+        let object = json!({
+            "id": "obj_0", "class_name": "car", "confidence": 0.9,
+            "bounding_box": {"x": 0.0, "y": 0.0, "width": 1.0, "height": 1.0},
+        });
+        json!({"frame_id": "f", "objects": vec![object; count], "retired_tracks": []})
+    }
+
+    fn metrics(kind: ModelType, primary: &Value, shadow: &Value) -> BTreeMap<&'static str, f64> {
+        divergence(kind, primary, shadow).into_iter().collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-5, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn trajectory_divergence_measures_displacement() {
+        let primary = trajectory(&[(0.0, 0.0), (1.0, 0.0)], 0.8);
+        // 3-4-5 off at the end
+        let shadow = trajectory(&[(0.0, 1.0), (4.0, 4.0)], 0.5);
+        let metrics = metrics(ModelType::TrajectoryPrediction, &primary, &shadow);
+        assert_close(metrics["confidence_delta"], 0.3);
+        assert_close(metrics["displacement_error"], 3.0);
+        assert_close(metrics["final_displacement_error"], 5.0);
+    }
+
+    #[test]
+    fn trajectories_without_points_only_compare_confidence() {
+        let metrics = metrics(ModelType::TrajectoryPrediction, &trajectory(&[], 0.8), &trajectory(&[], 0.8));
+        assert_eq!(metrics.keys().copied().collect::<Vec<_>>(), vec!["confidence_delta"]);
+        assert_close(metrics["confidence_delta"], 0.0);
+    }
+
+    #[test]
+    fn anomaly_divergence_flags_disagreement() {
+        let disagreeing = metrics(ModelType::AnomalyDetection, &anomaly(4.0, true), &anomaly(2.5, false));
+        assert_eq!(disagreeing["flag_disagreement"], 1.0);
+        assert_close(disagreeing["score_delta"], 1.5);
+
+        let agreeing = metrics(ModelType::AnomalyDetection, &anomaly(4.0, true), &anomaly(4.0, true));
+        assert_eq!(agreeing["flag_disagreement"], 0.0);
+        assert_eq!(agreeing["score_delta"], 0.0);
+    }
+
+    #[test]
+    fn object_divergence_counts_detections() {
+        let metrics = metrics(ModelType::ObjectDetection, &objects(2), &objects(5));
+        assert_eq!(metrics["count_delta"], 3.0);
+    }
+
+    #[test]
+    fn fusion_divergence_compares_confidence_and_quality() {
+        let fusion = |confidence: f32, quality: &str| {
+            json!({"overall_confidence": confidence, "sensor_statuses": [], "fusion_quality": quality})
+        };
+        let metrics = metrics(ModelType::SensorFusion, &fusion(0.9, "high"), &fusion(0.6, "medium"));
+        assert_close(metrics["confidence_delta"], 0.3);
+        assert_eq!(metrics["quality_disagreement"], 1.0);
+    }
+
+    #[test]
+    fn outputs_of_another_shape_have_no_divergence() {
+        for kind in ModelType::ALL {
+            assert!(divergence(kind, &json!({"unexpected": true}), &json!({"unexpected": true})).is_empty(), "{:?}", kind);
+        }
+    }
+}
@@ -1,70 +1,473 @@
 use serde::{Deserialize, Serialize};
-use ndarray::{Array1, Array2};
-use rand::Rng;
+use dashmap::DashMap;
+use rand::rngs::StdRng;
+use schemars::JsonSchema;
+use std::collections::BTreeMap;
+use tracing::warn;
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::{isolation_forest::IsolationForest, ModelError};
+use crate::ml::model::Model;
+
+const MODEL_VERSION: &str = "1.0.0";
+
+// Baseline tuning. Scores are z-scores against the rolling per-sensor baseline.
+const DEFAULT_THRESHOLD: f32 = 3.0;
+const EWMA_ALPHA: f64 = 0.05;
+const WARMUP_SAMPLES: u64 = 10;
+const MIN_STD_DEV: f64 = 1e-3;
+// Samples are clamped to this many std devs before updating, so a single spike
+// cannot drag the baseline while a sustained shift is still learned
+const UPDATE_CLIP_SIGMA: f64 = 4.0;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct SensorData {
     pub sensor_type: String,
     pub values: Vec<f32>,
     pub timestamp: i64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct AnomalyDetectionInput {
     pub sensor_readings: Vec<SensorData>,
+    // Replaces every configured threshold for this request only
+    #[serde(default)]
+    pub threshold: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyMethod {
+    Baseline,
+    IsolationForest,
+}
+
+// Per-sensor entries override the global threshold for that sensor_type
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnomalyThresholds {
+    pub global: f32,
+    pub sensors: BTreeMap<String, f32>,
+}
+
+impl AnomalyThresholds {
+    pub fn new(global: f32) -> Self {
+        Self {
+            global,
+            sensors: BTreeMap::new(),
+        }
+    }
+
+    pub fn for_sensor(&self, sensor_type: &str) -> f32 {
+        self.sensors.get(sensor_type).copied().unwrap_or(self.global)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnomalyThresholdConfig {
+    pub method: AnomalyMethod,
+    pub min: f32,
+    pub max: f32,
+    pub thresholds: AnomalyThresholds,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AnomalyDetectionOutput {
     pub anomaly_score: f32,
     pub is_anomaly: bool,
+    // The global threshold, or the request's override. Sensors with their own threshold
+    // are judged by the one listed for them in sensor_thresholds.
     pub threshold: f32,
     pub sensor_scores: Vec<(String, f32)>,
+    // The threshold applied to each entry of sensor_scores
+    pub sensor_thresholds: Vec<(String, f32)>,
+    pub method: AnomalyMethod,
+}
+
+impl AnomalyMethod {
+    // Valid threshold range for the method's score scale
+    pub fn threshold_range(self) -> (f32, f32) {
+        match self {
+            AnomalyMethod::Baseline => (0.1, 50.0),
+            AnomalyMethod::IsolationForest => (0.01, 0.99),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SensorBaseline {
+    pub sensor_type: String,
+    pub samples: u64,
+    pub means: Vec<f32>,
+    pub std_devs: Vec<f32>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ChannelStats {
+    mean: f64,
+    variance: f64,
+}
+
+#[derive(Default)]
+struct BaselineState {
+    samples: u64,
+    channels: Vec<ChannelStats>,
+}
+
+impl BaselineState {
+    // Largest absolute z-score across channels, 0 until the baseline has warmed up
+    fn score(&self, values: &[f32]) -> f32 {
+        if self.samples < WARMUP_SAMPLES {
+            return 0.0;
+        }
+
+        self.channels
+            .iter()
+            .zip(values)
+            .map(|(stats, &value)| {
+                let std_dev = stats.variance.sqrt().max(MIN_STD_DEV);
+                ((value as f64 - stats.mean) / std_dev).abs()
+            })
+            .fold(0.0, f64::max) as f32
+    }
+
+    fn update(&mut self, values: &[f32]) {
+        // Plain running average while warming up, exponential weighting afterwards
+        let alpha = (1.0 / (self.samples + 1) as f64).max(EWMA_ALPHA);
+        let warmed_up = self.samples >= WARMUP_SAMPLES;
+
+        for (stats, &value) in self.channels.iter_mut().zip(values) {
+            let mut value = value as f64;
+            let limit = UPDATE_CLIP_SIGMA * stats.variance.sqrt().max(MIN_STD_DEV);
+            // clamp panics on bounds that are not finite
+            let (low, high) = (stats.mean - limit, stats.mean + limit);
+            if warmed_up && low.is_finite() && high.is_finite() {
+                value = value.clamp(low, high);
+            }
+
+            let diff = value - stats.mean;
+            let increment = alpha * diff;
+            stats.mean += increment;
+            stats.variance = (1.0 - alpha) * (stats.variance + diff * increment);
+        }
+        self.samples += 1;
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnomalyParams {
+    // Baseline threshold, a trained forest brings its own
+    pub threshold: Option<f32>,
+    // Baseline overrides by sensor_type
+    pub sensor_thresholds: Option<BTreeMap<String, f32>>,
+    pub forest: Option<IsolationForest>,
 }
 
 pub struct AnomalyDetector {
-    threshold: f32,
-    // In production, this would be an autoencoder or isolation forest
+    thresholds: AnomalyThresholds,
+    // Rolling per-sensor_type baselines, one EWMA mean/variance per value channel
+    baselines: DashMap<String, BaselineState>,
+    // Once trained, the forest scores readings and the baselines keep learning in the background
+    forest: Option<IsolationForest>,
 }
 
 impl AnomalyDetector {
     pub fn new() -> Self {
         Self {
-            threshold: 0.85,
+            thresholds: AnomalyThresholds::new(DEFAULT_THRESHOLD),
+            baselines: DashMap::new(),
+            forest: None,
         }
     }
 
-    pub fn detect(&self, input: &AnomalyDetectionInput) -> AnomalyDetectionOutput {
-        let mut rng = rand::thread_rng();
+    pub fn with_params(params: AnomalyParams) -> Result<Self, ModelError> {
+        let mut detector = Self::new();
+        if let Some(threshold) = params.threshold {
+            detector.update_threshold(None, threshold)?;
+        }
+        for (sensor_type, threshold) in params.sensor_thresholds.unwrap_or_default() {
+            detector.update_threshold(Some(&sensor_type), threshold)?;
+        }
+        // Last, so the thresholds above go to the baseline rather than the forest
+        detector.set_forest(params.forest);
+        Ok(detector)
+    }
+
+    pub fn detect(&self, input: &AnomalyDetectionInput) -> anyhow::Result<AnomalyDetectionOutput> {
+        // Checked up front, a non-finite value would poison the baseline for good
+        if let Some(sensor) = input
+            .sensor_readings
+            .iter()
+            .find(|sensor| sensor.values.iter().any(|value| !value.is_finite()))
+        {
+            return Err(ModelError::InvalidInput(format!(
+                "sensor {} has a value that is not a finite number",
+                sensor.sensor_type
+            ))
+            .into());
+        }
+        if let Some(threshold) = input.threshold {
+            self.check_threshold(threshold)?;
+        }
         let mut sensor_scores = Vec::new();
-        
-        // Calculate anomaly score for each sensor
+
+        // Score each reading against its baseline before folding it in
         for sensor in &input.sensor_readings {
-            let mean = sensor.values.iter().sum::<f32>() / sensor.values.len() as f32;
-            let variance = sensor.values.iter()
-                .map(|x| (x - mean).powi(2))
-                .sum::<f32>() / sensor.values.len() as f32;
-            
-            // Simple anomaly score based on variance
-            let score = variance.sqrt() / (mean + 0.001);
+            if sensor.values.is_empty() {
+                sensor_scores.push((sensor.sensor_type.clone(), 0.0));
+                continue;
+            }
+
+            let mut baseline = self.baselines.entry(sensor.sensor_type.clone()).or_default();
+            if baseline.channels.len() != sensor.values.len() {
+                if baseline.samples > 0 {
+                    warn!(
+                        "Sensor {} changed from {} to {} channels, resetting baseline",
+                        sensor.sensor_type,
+                        baseline.channels.len(),
+                        sensor.values.len()
+                    );
+                }
+                *baseline = BaselineState {
+                    samples: 0,
+                    channels: vec![ChannelStats::default(); sensor.values.len()],
+                };
+            }
+
+            let baseline_score = baseline.score(&sensor.values);
+            baseline.update(&sensor.values);
+            drop(baseline);
+
+            // Sensors the forest was not trained on have no comparable score
+            let score = match &self.forest {
+                Some(forest) => forest.score(sensor)?.unwrap_or(0.0),
+                None => baseline_score,
+            };
             sensor_scores.push((sensor.sensor_type.clone(), score));
         }
-        
-        // Overall anomaly score
+
+        // Overall anomaly score is driven by the most deviant sensor
         let anomaly_score = sensor_scores.iter()
-            .map(|(_, score)| score)
-            .sum::<f32>() / sensor_scores.len() as f32
-            + rng.gen_range(-0.1..0.1);
-        
-        AnomalyDetectionOutput {
+            .map(|(_, score)| *score)
+            .fold(0.0, f32::max);
+
+        let thresholds = self.thresholds();
+        let sensor_thresholds: Vec<(String, f32)> = sensor_scores
+            .iter()
+            .map(|(sensor_type, _)| {
+                let threshold = input.threshold.unwrap_or_else(|| thresholds.for_sensor(sensor_type));
+                (sensor_type.clone(), threshold)
+            })
+            .collect();
+        let is_anomaly = sensor_scores
+            .iter()
+            .zip(&sensor_thresholds)
+            .any(|((_, score), (_, threshold))| score > threshold);
+
+        Ok(AnomalyDetectionOutput {
             anomaly_score,
-            is_anomaly: anomaly_score > self.threshold,
-            threshold: self.threshold,
+            is_anomaly,
+            threshold: input.threshold.unwrap_or(thresholds.global),
             sensor_scores,
+            sensor_thresholds,
+            method: self.method(),
+        })
+    }
+
+    pub fn baselines(&self) -> Vec<SensorBaseline> {
+        let mut baselines: Vec<SensorBaseline> = self.baselines
+            .iter()
+            .map(|entry| SensorBaseline {
+                sensor_type: entry.key().clone(),
+                samples: entry.samples,
+                means: entry.channels.iter().map(|c| c.mean as f32).collect(),
+                std_devs: entry.channels.iter().map(|c| c.variance.sqrt() as f32).collect(),
+            })
+            .collect();
+        baselines.sort_by(|a, b| a.sensor_type.cmp(&b.sensor_type));
+        baselines
+    }
+
+    // Returns how many baselines were dropped
+    pub fn reset_baselines(&self, sensor_type: Option<&str>) -> usize {
+        match sensor_type {
+            Some(sensor_type) => self.baselines.remove(sensor_type).map_or(0, |_| 1),
+            None => {
+                let count = self.baselines.len();
+                self.baselines.clear();
+                count
+            }
+        }
+    }
+
+    pub fn forest(&self) -> Option<&IsolationForest> {
+        self.forest.as_ref()
+    }
+
+    pub fn set_forest(&mut self, forest: Option<IsolationForest>) {
+        self.forest = forest;
+    }
+
+    pub fn method(&self) -> AnomalyMethod {
+        match self.forest {
+            Some(_) => AnomalyMethod::IsolationForest,
+            None => AnomalyMethod::Baseline,
+        }
+    }
+
+    // Thresholds live with the scoring method, since each has its own scale
+    pub fn thresholds(&self) -> &AnomalyThresholds {
+        match &self.forest {
+            Some(forest) => forest.thresholds(),
+            None => &self.thresholds,
+        }
+    }
+
+    pub fn threshold_config(&self) -> AnomalyThresholdConfig {
+        let method = self.method();
+        let (min, max) = method.threshold_range();
+        AnomalyThresholdConfig {
+            method,
+            min,
+            max,
+            thresholds: self.thresholds().clone(),
+        }
+    }
+
+    fn check_threshold(&self, threshold: f32) -> Result<(), ModelError> {
+        let method = self.method();
+        let (min, max) = method.threshold_range();
+        if !(min..=max).contains(&threshold) {
+            return Err(ModelError::InvalidInput(format!(
+                "{:?} threshold must be between {} and {}, got {}",
+                method, min, max, threshold
+            )));
+        }
+        Ok(())
+    }
+
+    // Without a sensor_type the global threshold is updated
+    pub fn update_threshold(&mut self, sensor_type: Option<&str>, new_threshold: f32) -> Result<(), ModelError> {
+        self.check_threshold(new_threshold)?;
+
+        let thresholds = match &mut self.forest {
+            Some(forest) => forest.thresholds_mut(),
+            None => &mut self.thresholds,
+        };
+        match sensor_type {
+            Some(sensor_type) => {
+                thresholds.sensors.insert(sensor_type.to_string(), new_threshold);
+            }
+            None => thresholds.global = new_threshold,
+        }
+        Ok(())
+    }
+
+    // Returns whether the sensor had an override
+    pub fn clear_sensor_threshold(&mut self, sensor_type: &str) -> bool {
+        let thresholds = match &mut self.forest {
+            Some(forest) => forest.thresholds_mut(),
+            None => &mut self.thresholds,
+        };
+        thresholds.sensors.remove(sensor_type).is_some()
+    }
+}
+
+impl Model for AnomalyDetector {
+    type Input = AnomalyDetectionInput;
+    type Output = AnomalyDetectionOutput;
+    type Params = AnomalyParams;
+
+    fn name(&self) -> &'static str {
+        match self.method() {
+            AnomalyMethod::Baseline => "ewma_baseline",
+            AnomalyMethod::IsolationForest => "isolation_forest",
+        }
+    }
+
+    fn version(&self) -> String {
+        MODEL_VERSION.to_string()
+    }
+
+    // Baselines are learned from live traffic and not part of the params
+    fn params(&self) -> Self::Params {
+        AnomalyParams {
+            threshold: Some(self.thresholds.global),
+            sensor_thresholds: Some(self.thresholds.sensors.clone()).filter(|sensors| !sensors.is_empty()),
+            forest: self.forest.clone(),
+        }
+    }
+
+    fn predict(&self, input: &Self::Input, _rng: &mut StdRng) -> anyhow::Result<Self::Output> {
+        self.detect(input)
+    }
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(sensors: &[&str], value: f32, threshold: Option<f32>) -> AnomalyDetectionInput {
+        AnomalyDetectionInput {
+            sensor_readings: sensors
+                .iter()
+                .map(|sensor_type| SensorData {
+                    sensor_type: sensor_type.to_string(),
+                    values: vec![value],
+                    timestamp: 0,
+                })
+                .collect(),
+            threshold,
         }
     }
 
-    pub fn update_threshold(&mut self, new_threshold: f32) {
-        self.threshold = new_threshold;
+    // Baselines of mean 0.5 and std dev 0.5, so a reading of 3 scores about 5
+    fn warmed_up(sensors: &[&str]) -> AnomalyDetector {
+        let detector = AnomalyDetector::new();
+        for i in 0..2 * WARMUP_SAMPLES {
+            detector.detect(&readings(sensors, (i % 2) as f32, None)).unwrap();
+        }
+        detector
+    }
+
+    #[test]
+    fn sensor_overrides_are_reported_with_their_scores() {
+        let mut detector = warmed_up(&["lidar", "radar"]);
+        detector.update_threshold(Some("lidar"), 10.0).unwrap();
+
+        let output = detector.detect(&readings(&["lidar", "radar"], 3.0, None)).unwrap();
+        assert_eq!(output.threshold, DEFAULT_THRESHOLD);
+        assert_eq!(
+            output.sensor_thresholds,
+            vec![("lidar".to_string(), 10.0), ("radar".to_string(), DEFAULT_THRESHOLD)]
+        );
+        assert!(output.is_anomaly);
+    }
+
+    #[test]
+    fn a_sensor_under_its_override_is_not_anomalous() {
+        let mut detector = warmed_up(&["lidar"]);
+        detector.update_threshold(Some("lidar"), 10.0).unwrap();
+
+        let output = detector.detect(&readings(&["lidar"], 3.0, None)).unwrap();
+        assert!(output.anomaly_score > DEFAULT_THRESHOLD, "{:?}", output);
+        assert_eq!(output.sensor_thresholds, vec![("lidar".to_string(), 10.0)]);
+        assert!(!output.is_anomaly);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn a_request_threshold_replaces_every_override() {
+        let mut detector = warmed_up(&["lidar"]);
+        detector.update_threshold(Some("lidar"), 10.0).unwrap();
+
+        let output = detector.detect(&readings(&["lidar"], 3.0, Some(2.0))).unwrap();
+        assert_eq!(output.threshold, 2.0);
+        assert_eq!(output.sensor_thresholds, vec![("lidar".to_string(), 2.0)]);
+        assert!(output.is_anomaly);
+    }
+}
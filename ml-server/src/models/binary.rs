@@ -0,0 +1,182 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{ErrorMessage, ModelType};
+
+// WebSocket subprotocols a client can offer to pick the binary frame encoding
+pub const BINCODE_PROTOCOL: &str = "ml.bincode";
+pub const MSGPACK_PROTOCOL: &str = "ml.msgpack";
+
+// Binary frames skip the JSON envelope. The model input and output are encoded
+// separately with the same encoding, so they decode straight into the model's own
+// types without going through serde_json::Value.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryRequest {
+    Inference {
+        request_id: Option<String>,
+        seed: Option<u64>,
+        // Registered model name, the default instances are named after their kind
+        model: String,
+        #[serde(with = "serde_bytes")]
+        input: Vec<u8>,
+    },
+    Heartbeat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryResponse {
+    Inference {
+        request_id: Option<String>,
+        model_type: ModelType,
+        model: String,
+        version: String,
+        #[serde(with = "serde_bytes")]
+        output: Vec<u8>,
+        latency_ms: f64,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    },
+    Error(ErrorMessage),
+    Heartbeat {
+        timestamp: chrono::DateTime<chrono::Utc>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WireEncoding {
+    #[default]
+    Bincode,
+    MessagePack,
+}
+
+impl WireEncoding {
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            BINCODE_PROTOCOL => Some(Self::Bincode),
+            MSGPACK_PROTOCOL => Some(Self::MessagePack),
+            _ => None,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Self::Bincode => bincode::serialize(value)?,
+            // Named fields keep MessagePack frames readable from JS without a schema
+            Self::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            Self::Bincode => bincode::deserialize(data)?,
+            Self::MessagePack => rmp_serde::from_slice(data)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{frame::EncodedImage, objects::ObjectDetectionInput};
+
+    const ENCODINGS: [WireEncoding; 2] = [WireEncoding::Bincode, WireEncoding::MessagePack];
+
+    fn inference(input: Vec<u8>) -> BinaryRequest {
+        BinaryRequest::Inference {
+            request_id: Some("r1".to_string()),
+            seed: Some(u64::MAX),
+            model: "object_detection".to_string(),
+            input,
+        }
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        for encoding in ENCODINGS {
+            let input = vec![0, 1, 2, 255];
+            let decoded: BinaryRequest = encoding.decode(&encoding.encode(&inference(input.clone())).unwrap()).unwrap();
+            match decoded {
+                BinaryRequest::Inference { request_id, seed, model, input: decoded_input } => {
+                    assert_eq!(request_id.as_deref(), Some("r1"));
+                    assert_eq!(seed, Some(u64::MAX));
+                    assert_eq!(model, "object_detection");
+                    assert_eq!(decoded_input, input);
+                }
+                other => panic!("{:?} decoded as {:?}", encoding, other),
+            }
+
+            let heartbeat: BinaryRequest = encoding.decode(&encoding.encode(&BinaryRequest::Heartbeat).unwrap()).unwrap();
+            assert!(matches!(heartbeat, BinaryRequest::Heartbeat), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn model_inputs_round_trip_inside_requests() {
+        for encoding in ENCODINGS {
+            let frame = ObjectDetectionInput {
+                frame_id: "f1".to_string(),
+                timestamp: 1_700_000_000_000,
+                stream_id: Some("cam".to_string()),
+                image: None,
+                encoded_image: Some(EncodedImage(vec![0x89, b'P', b'N', b'G'])),
+                simulate_complex: true,
+                postprocess: Default::default(),
+            };
+            let request = inference(encoding.encode(&frame).unwrap());
+            let BinaryRequest::Inference { input, .. } = encoding.decode(&encoding.encode(&request).unwrap()).unwrap() else {
+                panic!("{:?} lost the inference request", encoding);
+            };
+            let decoded: ObjectDetectionInput = encoding.decode(&input).unwrap();
+            assert_eq!(decoded.frame_id, "f1");
+            assert_eq!(decoded.timestamp, frame.timestamp);
+            assert_eq!(decoded.stream_id.as_deref(), Some("cam"));
+            assert_eq!(decoded.encoded_image.unwrap().0, vec![0x89, b'P', b'N', b'G']);
+            assert!(decoded.simulate_complex);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for encoding in ENCODINGS {
+            let timestamp = chrono::Utc::now();
+            let response = BinaryResponse::Inference {
+                request_id: None,
+                model_type: ModelType::ObjectDetection,
+                model: "object_detection".to_string(),
+                version: "0.1.0".to_string(),
+                output: vec![7; 3],
+                latency_ms: 1.5,
+                timestamp,
+            };
+            let decoded: BinaryResponse = encoding.decode(&encoding.encode(&response).unwrap()).unwrap();
+            let BinaryResponse::Inference { output, latency_ms, timestamp: decoded_timestamp, .. } = decoded else {
+                panic!("{:?} lost the inference response", encoding);
+            };
+            assert_eq!((output, latency_ms, decoded_timestamp), (vec![7; 3], 1.5, timestamp));
+        }
+    }
+
+    #[test]
+    fn message_pack_frames_carry_field_names() {
+        let contains = |frame: &[u8], name: &str| frame.windows(name.len()).any(|window| window == name.as_bytes());
+        let frame = WireEncoding::MessagePack.encode(&inference(vec![1])).unwrap();
+        assert!(contains(&frame, "request_id") && contains(&frame, "model"));
+        let frame = WireEncoding::Bincode.encode(&inference(vec![1])).unwrap();
+        assert!(!contains(&frame, "request_id"));
+    }
+
+    #[test]
+    fn truncated_frames_fail_to_decode() {
+        for encoding in ENCODINGS {
+            let frame = encoding.encode(&inference(vec![1, 2, 3])).unwrap();
+            assert!(encoding.decode::<BinaryRequest>(&frame[..frame.len() - 2]).is_err(), "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn protocols_pick_the_encoding() {
+        assert_eq!(WireEncoding::from_protocol(BINCODE_PROTOCOL), Some(WireEncoding::Bincode));
+        assert_eq!(WireEncoding::from_protocol(MSGPACK_PROTOCOL), Some(WireEncoding::MessagePack));
+        assert_eq!(WireEncoding::from_protocol("json"), None);
+    }
+}
@@ -0,0 +1,275 @@
+use base64::Engine;
+use image::{imageops, ImageFormat, RgbImage};
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io::Cursor;
+
+use super::{objects::BoundingBox, ModelError};
+
+// Larger frames are rejected before any pixels are decoded
+pub const MAX_FRAME_SIDE: u32 = 4096;
+
+// Grey used by YOLO style detectors for letterbox borders
+const LETTERBOX_FILL: u8 = 114;
+
+// 8-bit RGB, row major, 3 bytes per pixel
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    #[serde(with = "serde_bytes")]
+    #[schemars(with = "Vec<u8>")]
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    pub fn validate(&self) -> Result<(), ModelError> {
+        check_dimensions(self.width, self.height)?;
+        let expected = self.width as usize * self.height as usize * 3;
+        if self.rgb.len() != expected {
+            return Err(ModelError::InvalidInput(format!(
+                "a {}x{} RGB image needs {} bytes, got {}",
+                self.width,
+                self.height,
+                expected,
+                self.rgb.len()
+            )));
+        }
+        Ok(())
+    }
+
+    // Scaled to fit width x height with the aspect ratio kept, the rest padded
+    pub fn letterbox(&self, width: u32, height: u32) -> Letterbox {
+        let scale = (width as f32 / self.width as f32).min(height as f32 / self.height as f32);
+        let scaled_width = ((self.width as f32 * scale).round() as u32).clamp(1, width);
+        let scaled_height = ((self.height as f32 * scale).round() as u32).clamp(1, height);
+        let pad_x = (width - scaled_width) / 2;
+        let pad_y = (height - scaled_height) / 2;
+
+        let source = RgbImage::from_raw(self.width, self.height, self.rgb.clone())
+            .expect("validated images match their dimensions");
+        let scaled = imageops::resize(&source, scaled_width, scaled_height, imageops::FilterType::Triangle);
+        let mut canvas = RgbImage::from_pixel(width, height, image::Rgb([LETTERBOX_FILL; 3]));
+        imageops::replace(&mut canvas, &scaled, pad_x as i64, pad_y as i64);
+
+        Letterbox {
+            image: Image {
+                width,
+                height,
+                rgb: canvas.into_raw(),
+            },
+            source_width: self.width,
+            source_height: self.height,
+            scale_x: scaled_width as f32 / self.width as f32,
+            scale_y: scaled_height as f32 / self.height as f32,
+            pad_x: pad_x as f32,
+            pad_y: pad_y as f32,
+        }
+    }
+}
+
+pub struct Letterbox {
+    pub image: Image,
+    source_width: u32,
+    source_height: u32,
+    scale_x: f32,
+    scale_y: f32,
+    pad_x: f32,
+    pad_y: f32,
+}
+
+impl Letterbox {
+    // Maps a box on the letterboxed image back onto the original, clipped to its edges
+    pub fn to_source(&self, bounding_box: &BoundingBox) -> BoundingBox {
+        let left = ((bounding_box.x - self.pad_x) / self.scale_x).clamp(0.0, self.source_width as f32);
+        let top = ((bounding_box.y - self.pad_y) / self.scale_y).clamp(0.0, self.source_height as f32);
+        let right = ((bounding_box.x + bounding_box.width - self.pad_x) / self.scale_x)
+            .clamp(0.0, self.source_width as f32);
+        let bottom = ((bounding_box.y + bounding_box.height - self.pad_y) / self.scale_y)
+            .clamp(0.0, self.source_height as f32);
+        BoundingBox {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        }
+    }
+}
+
+// A JPEG or PNG file. JSON carries it as base64, optionally as a data URL, binary
+// frames as plain bytes.
+#[derive(Debug, Clone)]
+pub struct EncodedImage(pub Vec<u8>);
+
+impl EncodedImage {
+    pub fn decode(&self) -> Result<Image, ModelError> {
+        let format = match image::guess_format(&self.0) {
+            Ok(format @ (ImageFormat::Jpeg | ImageFormat::Png)) => format,
+            Ok(format) => {
+                return Err(ModelError::InvalidInput(format!(
+                    "unsupported image format {:?}, expected JPEG or PNG",
+                    format
+                )));
+            }
+            Err(_) => {
+                return Err(ModelError::InvalidInput("image is not a JPEG or PNG file".to_string()));
+            }
+        };
+
+        let (width, height) = image::io::Reader::with_format(Cursor::new(&self.0), format)
+            .into_dimensions()
+            .map_err(|e| ModelError::InvalidInput(format!("cannot read {:?} header: {}", format, e)))?;
+        check_dimensions(width, height)?;
+
+        let decoded = image::load_from_memory_with_format(&self.0, format)
+            .map_err(|e| ModelError::InvalidInput(format!("cannot decode {:?} image: {}", format, e)))?
+            .to_rgb8();
+        Ok(Image {
+            width: decoded.width(),
+            height: decoded.height(),
+            rgb: decoded.into_raw(),
+        })
+    }
+}
+
+impl Serialize for EncodedImage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for EncodedImage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if !deserializer.is_human_readable() {
+            return serde_bytes::ByteBuf::deserialize(deserializer).map(|bytes| Self(bytes.into_vec()));
+        }
+        let text = String::deserialize(deserializer)?;
+        let data = match text.split_once(";base64,") {
+            Some((prefix, data)) if prefix.starts_with("data:") => data,
+            _ => text.as_str(),
+        };
+        base64::engine::general_purpose::STANDARD
+            .decode(data.trim())
+            .map(Self)
+            .map_err(|e| serde::de::Error::custom(format!("image is not valid base64: {}", e)))
+    }
+}
+
+fn check_dimensions(width: u32, height: u32) -> Result<(), ModelError> {
+    if width == 0 || height == 0 || width > MAX_FRAME_SIDE || height > MAX_FRAME_SIDE {
+        return Err(ModelError::InvalidInput(format!(
+            "image is {}x{}, each side must be between 1 and {} pixels",
+            width, height, MAX_FRAME_SIDE
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(width: u32, height: u32) -> Image {
+        let rgb = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+        Image { width, height, rgb }
+    }
+
+    fn png(image: &Image) -> Vec<u8> {
+        let buffer = RgbImage::from_raw(image.width, image.height, image.rgb.clone()).unwrap();
+        let mut bytes = Cursor::new(Vec::new());
+        buffer.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    fn from_json(text: String) -> Result<EncodedImage, serde_json::Error> {
+        serde_json::from_value(serde_json::Value::String(text))
+    }
+
+    fn assert_close(a: &BoundingBox, b: &BoundingBox) {
+        let (a, b) = ([a.x, a.y, a.width, a.height], [b.x, b.y, b.width, b.height]);
+        assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 0.01), "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn base64_png_decodes_to_its_pixels() {
+        let original = image(6, 4);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(png(&original));
+        let decoded = from_json(encoded).unwrap().decode().unwrap();
+        assert_eq!((decoded.width, decoded.height), (6, 4));
+        assert_eq!(decoded.rgb, original.rgb);
+    }
+
+    #[test]
+    fn data_urls_decode_like_plain_base64() {
+        let bytes = png(&image(3, 3));
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&bytes);
+        let from_url = from_json(format!("data:image/png;base64,{}", encoded)).unwrap();
+        assert_eq!(from_url.0, bytes);
+        // Surrounding whitespace is tolerated
+        assert_eq!(from_json(format!("{}\n", encoded)).unwrap().0, bytes);
+    }
+
+    #[test]
+    fn invalid_base64_is_rejected() {
+        let error = from_json("not base64!".to_string()).unwrap_err();
+        assert!(error.to_string().contains("not valid base64"), "{}", error);
+    }
+
+    #[test]
+    fn only_jpeg_and_png_decode() {
+        let error = EncodedImage(b"plain text".to_vec()).decode().unwrap_err();
+        assert!(error.to_string().contains("not a JPEG or PNG"), "{}", error);
+    }
+
+    #[test]
+    fn oversized_images_are_rejected() {
+        let error = EncodedImage(png(&image(1, MAX_FRAME_SIDE + 1))).decode().unwrap_err();
+        assert!(error.to_string().contains("each side must be between"), "{}", error);
+    }
+
+    #[test]
+    fn raw_images_must_match_their_dimensions() {
+        assert!(image(4, 2).validate().is_ok());
+        let mut short = image(4, 2);
+        short.rgb.pop();
+        assert!(short.validate().is_err());
+        assert!(image(0, 2).validate().is_err());
+    }
+
+    #[test]
+    fn letterbox_pads_the_short_side() {
+        let letterbox = image(200, 100).letterbox(64, 64);
+        assert_eq!((letterbox.image.width, letterbox.image.height), (64, 64));
+        assert_eq!(letterbox.image.rgb.len(), 64 * 64 * 3);
+        // Rows above the scaled image are border
+        assert!(letterbox.image.rgb[..64 * 3].iter().all(|&byte| byte == LETTERBOX_FILL));
+    }
+
+    #[test]
+    fn letterboxed_boxes_map_back_to_the_source() {
+        let letterbox = image(200, 100).letterbox(64, 64);
+        // Scaled by 0.32 and shifted down by 16 rows of padding
+        let source = BoundingBox { x: 20.0, y: 10.0, width: 40.0, height: 30.0 };
+        let letterboxed = BoundingBox {
+            x: source.x * 0.32,
+            y: source.y * 0.32 + 16.0,
+            width: source.width * 0.32,
+            height: source.height * 0.32,
+        };
+        assert_close(&letterbox.to_source(&letterboxed), &source);
+    }
+
+    #[test]
+    fn boxes_reaching_into_the_border_are_clipped() {
+        let letterbox = image(200, 100).letterbox(64, 64);
+        let across_top = BoundingBox { x: -5.0, y: 0.0, width: 74.0, height: 26.0 };
+        assert_close(
+            &letterbox.to_source(&across_top),
+            &BoundingBox { x: 0.0, y: 0.0, width: 200.0, height: 31.25 },
+        );
+    }
+}
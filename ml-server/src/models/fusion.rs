@@ -1,5 +1,12 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use rand::{rngs::StdRng, Rng};
+use schemars::JsonSchema;
+use std::collections::{BTreeMap, HashMap};
+
+use super::ModelError;
+use crate::ml::model::Model;
+
+const MODEL_VERSION: &str = "0.1.0";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SensorStatus {
@@ -9,9 +16,11 @@ pub struct SensorStatus {
     pub last_update: i64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct FusionInput {
-    pub sensor_data: HashMap<String, bool>,
+    // Ordered, so a seeded rng is consumed in the same order on every call and the
+    // input hashes the same way
+    pub sensor_data: BTreeMap<String, bool>,
     pub timestamp: i64,
 }
 
@@ -22,6 +31,13 @@ pub struct FusionOutput {
     pub fusion_quality: String,
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FusionParams {
+    // Replaces the built-in weights, unlisted sensors fall back to the default weight
+    pub sensor_weights: Option<HashMap<String, f32>>,
+}
+
 pub struct SensorFusion {
     sensor_weights: HashMap<String, f32>,
 }
@@ -36,7 +52,17 @@ impl SensorFusion {
         Self { sensor_weights }
     }
 
-    pub fn fuse(&self, input: &FusionInput) -> FusionOutput {
+    pub fn with_params(params: FusionParams) -> Result<Self, ModelError> {
+        let Some(sensor_weights) = params.sensor_weights else {
+            return Ok(Self::new());
+        };
+        if sensor_weights.values().any(|weight| !weight.is_finite() || *weight < 0.0) {
+            return Err(ModelError::InvalidInput("sensor weights must be non-negative".to_string()));
+        }
+        Ok(Self { sensor_weights })
+    }
+
+    pub fn fuse<R: Rng>(&self, input: &FusionInput, rng: &mut R) -> FusionOutput {
         let mut sensor_statuses = Vec::new();
         let mut total_weight = 0.0;
         let mut weighted_confidence = 0.0;
@@ -44,7 +70,7 @@ impl SensorFusion {
         for (sensor_type, is_active) in &input.sensor_data {
             let weight = self.sensor_weights.get(sensor_type).unwrap_or(&0.2);
             let confidence = if *is_active {
-                0.9 + rand::random::<f32>() * 0.1
+                0.9 + rng.gen::<f32>() * 0.1
             } else {
                 0.0
             };
@@ -81,4 +107,34 @@ impl SensorFusion {
             fusion_quality,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Model for SensorFusion {
+    type Input = FusionInput;
+    type Output = FusionOutput;
+    type Params = FusionParams;
+
+    fn name(&self) -> &'static str {
+        "weighted_fusion"
+    }
+
+    fn version(&self) -> String {
+        MODEL_VERSION.to_string()
+    }
+
+    fn params(&self) -> Self::Params {
+        FusionParams {
+            sensor_weights: Some(self.sensor_weights.clone()),
+        }
+    }
+
+    fn predict(&self, input: &Self::Input, rng: &mut StdRng) -> anyhow::Result<Self::Output> {
+        Ok(self.fuse(input, rng))
+    }
+}
+
+impl Default for SensorFusion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
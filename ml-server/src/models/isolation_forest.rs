@@ -0,0 +1,410 @@
+use serde::{Deserialize, Serialize};
+use ndarray::{Array2, ArrayView1};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use super::{
+    anomaly::{AnomalyThresholds, SensorData},
+    ModelError, ModelType,
+};
+use crate::ml::artifact::ModelArtifact;
+
+const DEFAULT_TREES: usize = 100;
+const DEFAULT_SAMPLE_SIZE: usize = 256;
+const DEFAULT_CONTAMINATION: f32 = 0.02;
+const MIN_TRAINING_SAMPLES: usize = 8;
+// Bounds what one training request can cost
+const MAX_TREES: usize = 1000;
+const MAX_SAMPLE_SIZE: usize = 4096;
+// Version label of a persisted forest artifact
+const FOREST_ARTIFACT_VERSION: &str = "forest";
+const EULER_GAMMA: f64 = 0.577_215_664_901_532_9;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForestTrainingInput {
+    pub sensor_readings: Vec<SensorData>,
+    #[serde(default)]
+    pub n_trees: Option<usize>,
+    #[serde(default)]
+    pub sample_size: Option<usize>,
+    // Expected share of anomalies in the training data, used to place the threshold
+    #[serde(default)]
+    pub contamination: Option<f32>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForestSummary {
+    pub sensor_samples: Vec<(String, usize)>,
+    pub n_trees: usize,
+    pub sample_size: usize,
+    pub threshold: f32,
+    pub trained_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Node {
+    Leaf { size: usize },
+    Split { feature: usize, threshold: f64, left: usize, right: usize },
+}
+
+// Nodes are stored flat with child indices so the tree serializes without recursion
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IsolationTree {
+    nodes: Vec<Node>,
+}
+
+impl IsolationTree {
+    fn fit(data: &Array2<f64>, rows: &mut [usize], height_limit: usize, rng: &mut StdRng) -> Self {
+        let mut tree = Self { nodes: Vec::new() };
+        tree.grow(data, rows, 0, height_limit, rng);
+        tree
+    }
+
+    fn grow(&mut self, data: &Array2<f64>, rows: &mut [usize], depth: usize, height_limit: usize, rng: &mut StdRng) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(Node::Leaf { size: rows.len() });
+        if depth >= height_limit || rows.len() <= 1 {
+            return index;
+        }
+
+        // Only features that still vary within this node can separate it
+        let ranges: Vec<(usize, f64, f64)> = (0..data.ncols())
+            .filter_map(|feature| {
+                let (min, max) = rows.iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &row| {
+                    let value = data[[row, feature]];
+                    (lo.min(value), hi.max(value))
+                });
+                (max > min).then_some((feature, min, max))
+            })
+            .collect();
+        if ranges.is_empty() {
+            return index;
+        }
+
+        let (feature, min, max) = ranges[rng.gen_range(0..ranges.len())];
+        let threshold = rng.gen_range(min..max);
+
+        let mut split = 0;
+        for i in 0..rows.len() {
+            if data[[rows[i], feature]] < threshold {
+                rows.swap(i, split);
+                split += 1;
+            }
+        }
+
+        let (left_rows, right_rows) = rows.split_at_mut(split);
+        let left = self.grow(data, left_rows, depth + 1, height_limit, rng);
+        let right = self.grow(data, right_rows, depth + 1, height_limit, rng);
+        self.nodes[index] = Node::Split { feature, threshold, left, right };
+        index
+    }
+
+    fn path_length(&self, sample: ArrayView1<f64>) -> f64 {
+        let mut index = 0;
+        let mut depth = 0.0;
+        loop {
+            match self.nodes[index] {
+                Node::Leaf { size } => return depth + average_path_length(size),
+                Node::Split { feature, threshold, left, right } => {
+                    index = if sample[feature] < threshold { left } else { right };
+                    depth += 1.0;
+                }
+            }
+        }
+    }
+}
+
+// Expected path length of an unsuccessful BST search over n points, c(n) in the paper
+fn average_path_length(n: usize) -> f64 {
+    match n {
+        0 | 1 => 0.0,
+        2 => 1.0,
+        _ => {
+            let n = n as f64;
+            2.0 * ((n - 1.0).ln() + EULER_GAMMA) - 2.0 * (n - 1.0) / n
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SensorForest {
+    channels: usize,
+    training_samples: usize,
+    sample_size: usize,
+    trees: Vec<IsolationTree>,
+}
+
+impl SensorForest {
+    fn fit(data: &Array2<f64>, n_trees: usize, sample_size: usize, rng: &mut StdRng) -> Self {
+        let sample_size = sample_size.min(data.nrows());
+        let height_limit = (sample_size as f64).log2().ceil() as usize;
+
+        let trees = (0..n_trees)
+            .map(|_| {
+                let mut rows = rand::seq::index::sample(rng, data.nrows(), sample_size).into_vec();
+                IsolationTree::fit(data, &mut rows, height_limit, rng)
+            })
+            .collect();
+
+        Self {
+            channels: data.ncols(),
+            training_samples: data.nrows(),
+            sample_size,
+            trees,
+        }
+    }
+
+    // Standard isolation forest score in (0, 1]: ~0.5 is unremarkable, close to 1 is anomalous
+    fn score(&self, sample: ArrayView1<f64>) -> f64 {
+        let mean_path = self.trees.iter().map(|tree| tree.path_length(sample)).sum::<f64>() / self.trees.len() as f64;
+        2f64.powf(-mean_path / average_path_length(self.sample_size))
+    }
+}
+
+// One forest per sensor_type, each reading's values are the feature vector
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IsolationForest {
+    forests: HashMap<String, SensorForest>,
+    thresholds: AnomalyThresholds,
+    trained_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl IsolationForest {
+    pub fn fit(input: &ForestTrainingInput) -> Result<Self, ModelError> {
+        let n_trees = input.n_trees.unwrap_or(DEFAULT_TREES);
+        let sample_size = input.sample_size.unwrap_or(DEFAULT_SAMPLE_SIZE);
+        let contamination = input.contamination.unwrap_or(DEFAULT_CONTAMINATION);
+        if !(1..=MAX_TREES).contains(&n_trees) || !(2..=MAX_SAMPLE_SIZE).contains(&sample_size) {
+            return Err(ModelError::InvalidInput(format!(
+                "n_trees must be between 1 and {}, sample_size between 2 and {}",
+                MAX_TREES, MAX_SAMPLE_SIZE
+            )));
+        }
+        if !(0.0..0.5).contains(&contamination) {
+            return Err(ModelError::InvalidInput(format!(
+                "contamination must be in [0, 0.5), got {}",
+                contamination
+            )));
+        }
+
+        // Ordered so a fixed seed always visits sensors in the same order
+        let mut grouped: BTreeMap<&str, Vec<&[f32]>> = BTreeMap::new();
+        for reading in &input.sensor_readings {
+            grouped.entry(reading.sensor_type.as_str()).or_default().push(&reading.values);
+        }
+        if grouped.is_empty() {
+            return Err(ModelError::InvalidInput("no sensor readings to train on".to_string()));
+        }
+
+        let mut rng = match input.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        let mut forests = HashMap::new();
+        let mut training_scores = Vec::new();
+        for (sensor_type, samples) in grouped {
+            let data = Self::training_matrix(sensor_type, &samples)?;
+            let forest = SensorForest::fit(&data, n_trees, sample_size, &mut rng);
+            training_scores.extend(data.rows().into_iter().map(|row| forest.score(row)));
+            forests.insert(sensor_type.to_string(), forest);
+        }
+
+        // Place the threshold so roughly `contamination` of the training data scores above it
+        training_scores.sort_by(|a, b| a.total_cmp(b));
+        let cutoff = ((1.0 - contamination as f64) * (training_scores.len() - 1) as f64).round() as usize;
+
+        Ok(Self {
+            forests,
+            thresholds: AnomalyThresholds::new(training_scores[cutoff] as f32),
+            trained_at: chrono::Utc::now(),
+        })
+    }
+
+    fn training_matrix(sensor_type: &str, samples: &[&[f32]]) -> Result<Array2<f64>, ModelError> {
+        if samples.len() < MIN_TRAINING_SAMPLES {
+            return Err(ModelError::InvalidInput(format!(
+                "sensor {} has {} training samples, need at least {}",
+                sensor_type,
+                samples.len(),
+                MIN_TRAINING_SAMPLES
+            )));
+        }
+
+        let channels = samples[0].len();
+        if channels == 0 || samples.iter().any(|values| values.len() != channels) {
+            return Err(ModelError::InvalidInput(format!(
+                "sensor {} readings must all have the same non-zero number of values",
+                sensor_type
+            )));
+        }
+
+        // Split thresholds are drawn between feature bounds, which must be finite
+        if samples.iter().any(|values| values.iter().any(|value| !value.is_finite())) {
+            return Err(ModelError::InvalidInput(format!(
+                "sensor {} has a training value that is not a finite number",
+                sensor_type
+            )));
+        }
+
+        Ok(Array2::from_shape_fn((samples.len(), channels), |(row, col)| samples[row][col] as f64))
+    }
+
+    // None when the forest was not trained on this sensor type
+    pub fn score(&self, reading: &SensorData) -> Result<Option<f32>, ModelError> {
+        let Some(forest) = self.forests.get(&reading.sensor_type) else {
+            return Ok(None);
+        };
+        if reading.values.len() != forest.channels {
+            return Err(ModelError::InvalidInput(format!(
+                "sensor {} was trained with {} values per reading, got {}",
+                reading.sensor_type,
+                forest.channels,
+                reading.values.len()
+            )));
+        }
+
+        let sample = ndarray::Array1::from_iter(reading.values.iter().map(|&v| v as f64));
+        Ok(Some(forest.score(sample.view()) as f32))
+    }
+
+    pub fn thresholds(&self) -> &AnomalyThresholds {
+        &self.thresholds
+    }
+
+    pub fn thresholds_mut(&mut self) -> &mut AnomalyThresholds {
+        &mut self.thresholds
+    }
+
+    pub fn summary(&self) -> ForestSummary {
+        let mut sensor_samples: Vec<(String, usize)> = self.forests
+            .iter()
+            .map(|(sensor_type, forest)| (sensor_type.clone(), forest.training_samples))
+            .collect();
+        sensor_samples.sort();
+
+        let any = self.forests.values().next();
+        ForestSummary {
+            sensor_samples,
+            n_trees: any.map_or(0, |f| f.trees.len()),
+            sample_size: any.map_or(0, |f| f.sample_size),
+            threshold: self.thresholds.global,
+            trained_at: self.trained_at,
+        }
+    }
+
+    // Persisted as an anomaly detection artifact whose params hold just the forest
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut params = serde_json::Map::new();
+        params.insert("forest".to_string(), serde_json::to_value(self)?);
+        ModelArtifact {
+            kind: ModelType::AnomalyDetection,
+            version: FOREST_ARTIFACT_VERSION.to_string(),
+            created_at: self.trained_at,
+            params,
+        }
+        .save(path)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut artifact = ModelArtifact::load(path)?;
+        if artifact.kind != ModelType::AnomalyDetection {
+            anyhow::bail!("artifact holds a {:?} model, not an anomaly forest", artifact.kind);
+        }
+        let forest = artifact
+            .params
+            .remove("forest")
+            .ok_or_else(|| anyhow::anyhow!("artifact holds no forest"))?;
+        Ok(serde_json::from_value(forest)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(sensor_type: &str, values: Vec<f32>) -> SensorData {
+        SensorData { sensor_type: sensor_type.to_string(), values, timestamp: 0 }
+    }
+
+    // A 20x20 grid around (20, 50), one unit across
+    fn training_input(seed: u64) -> ForestTrainingInput {
+        let sensor_readings = (0..400)
+            .map(|i| reading("imu", vec![19.5 + (i % 20) as f32 * 0.05, 49.5 + (i / 20) as f32 * 0.05]))
+            .collect();
+        ForestTrainingInput {
+            sensor_readings,
+            n_trees: None,
+            sample_size: None,
+            contamination: None,
+            seed: Some(seed),
+        }
+    }
+
+    fn score(forest: &IsolationForest, values: Vec<f32>) -> f32 {
+        forest.score(&reading("imu", values)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn average_path_length_matches_the_paper() {
+        assert_eq!(average_path_length(1), 0.0);
+        assert_eq!(average_path_length(2), 1.0);
+        // 2 (ln 255 + gamma) - 2 * 255 / 256
+        assert!((average_path_length(256) - 10.2448).abs() < 1e-3, "{}", average_path_length(256));
+    }
+
+    #[test]
+    fn outlier_scores_higher_than_inlier() {
+        let forest = IsolationForest::fit(&training_input(7)).unwrap();
+        let inlier = score(&forest, vec![20.0, 50.0]);
+        let outlier = score(&forest, vec![35.0, 10.0]);
+        assert!(outlier > inlier, "outlier {} inlier {}", outlier, inlier);
+        assert!(outlier > forest.thresholds().global, "outlier {} threshold {}", outlier, forest.thresholds().global);
+        assert!(inlier <= forest.thresholds().global, "inlier {} threshold {}", inlier, forest.thresholds().global);
+    }
+
+    #[test]
+    fn same_seed_trains_the_same_forest() {
+        let first = IsolationForest::fit(&training_input(7)).unwrap();
+        let second = IsolationForest::fit(&training_input(7)).unwrap();
+        for values in [vec![20.0, 50.0], vec![21.0, 48.0], vec![35.0, 10.0]] {
+            assert_eq!(score(&first, values.clone()), score(&second, values));
+        }
+    }
+
+    #[test]
+    fn untrained_sensors_have_no_score() {
+        let forest = IsolationForest::fit(&training_input(7)).unwrap();
+        assert_eq!(forest.score(&reading("gps", vec![1.0, 2.0])).unwrap(), None);
+        assert!(forest.score(&reading("imu", vec![1.0])).is_err());
+    }
+
+    #[test]
+    fn training_rejects_unbounded_sizes_and_non_finite_values() {
+        for (n_trees, sample_size) in [(0, 256), (MAX_TREES + 1, 256), (100, 1), (100, MAX_SAMPLE_SIZE + 1)] {
+            let mut input = training_input(7);
+            input.n_trees = Some(n_trees);
+            input.sample_size = Some(sample_size);
+            assert!(IsolationForest::fit(&input).is_err(), "n_trees {} sample_size {}", n_trees, sample_size);
+        }
+
+        let mut input = training_input(7);
+        input.sensor_readings[3].values[1] = f32::INFINITY;
+        assert!(IsolationForest::fit(&input).is_err());
+    }
+
+    #[test]
+    fn saved_forest_scores_the_same_after_loading() {
+        let forest = IsolationForest::fit(&training_input(7)).unwrap();
+        let path = std::env::temp_dir().join(format!("forest-{}.mlmodel", std::process::id()));
+        forest.save(&path).unwrap();
+        let loaded = IsolationForest::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(score(&loaded, vec![35.0, 10.0]), score(&forest, vec![35.0, 10.0]));
+        assert_eq!(loaded.thresholds().global, forest.thresholds().global);
+    }
+}
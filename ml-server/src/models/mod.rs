@@ -2,8 +2,15 @@ use serde::{Deserialize, Serialize};
 
 pub mod trajectory;
 pub mod anomaly;
+pub mod isolation_forest;
 pub mod objects;
+pub mod frame;
+pub mod tracking;
+pub mod taxonomy;
 pub mod fusion;
+pub mod binary;
+#[cfg(feature = "onnx")]
+pub mod onnx;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, Eq, PartialEq)]
 #[serde(rename_all = "snake_case")]
@@ -14,18 +21,170 @@ pub enum ModelType {
     SensorFusion,
 }
 
+impl ModelType {
+    pub const ALL: [ModelType; 4] = [
+        ModelType::TrajectoryPrediction,
+        ModelType::AnomalyDetection,
+        ModelType::ObjectDetection,
+        ModelType::SensorFusion,
+    ];
+
+    // Every kind has a default instance registered under this name
+    pub fn default_name(self) -> &'static str {
+        match self {
+            ModelType::TrajectoryPrediction => "trajectory_prediction",
+            ModelType::AnomalyDetection => "anomaly_detection",
+            ModelType::ObjectDetection => "object_detection",
+            ModelType::SensorFusion => "sensor_fusion",
+        }
+    }
+}
+
+// Requests name a registered model, or just a kind to get its default instance
+pub fn resolve_model(model: Option<&str>, model_type: Option<ModelType>) -> Result<&str, ModelError> {
+    model
+        .or(model_type.map(ModelType::default_name))
+        .ok_or_else(|| ModelError::InvalidInput("either model or model_type is required".to_string()))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ModelError {
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("unknown model: {0}")]
+    UnknownModel(String),
+    #[error("model {0} is disabled")]
+    Unavailable(String),
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InferenceRequest {
-    pub model_type: ModelType,
+    #[serde(default)]
+    pub model_type: Option<ModelType>,
+    // Registered model name, takes precedence over model_type
+    #[serde(default)]
+    pub model: Option<String>,
     pub data: serde_json::Value,
+    // Makes stochastic models reproducible for this request
+    #[serde(default)]
+    pub seed: Option<u64>,
+    // Chosen by the client and echoed back so concurrent requests can be told apart
+    #[serde(default)]
+    pub request_id: Option<String>,
+    // Keeps a sticky experiment on one version, defaults to the connection
+    #[serde(default)]
+    pub client_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InferenceResponse {
     pub model_type: ModelType,
+    #[serde(default)]
+    pub model: String,
+    // The version that served this request, not necessarily the active one during an experiment
+    #[serde(default)]
+    pub version: String,
     pub prediction: serde_json::Value,
     pub latency_ms: f64,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchItem {
+    // Falls back to the batch's model, then model_type
+    #[serde(default)]
+    pub model_type: Option<ModelType>,
+    #[serde(default)]
+    pub model: Option<String>,
+    pub data: serde_json::Value,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+// Items may target different models and run in parallel, results come back in item order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchInferenceRequest {
+    #[serde(default)]
+    pub model_type: Option<ModelType>,
+    #[serde(default)]
+    pub model: Option<String>,
+    pub items: Vec<BatchItem>,
+    // Default for items without their own seed
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+// Exactly one of prediction and error is set
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchItemResult {
+    pub index: usize,
+    pub model_type: Option<ModelType>,
+    pub model: Option<String>,
+    pub version: Option<String>,
+    pub request_id: Option<String>,
+    pub prediction: Option<serde_json::Value>,
+    pub error: Option<ErrorMessage>,
+    pub latency_ms: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchInferenceResponse {
+    pub results: Vec<BatchItemResult>,
+    pub succeeded: usize,
+    pub failed: usize,
+    // Wall time for the whole batch, not the sum of item latencies
+    pub latency_ms: f64,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelChange {
+    ThresholdChanged,
+    BaselinesReset,
+    ForestTrained,
+    ForestCleared,
+    Registered,
+    Unregistered,
+    Reloaded,
+    RolledBack,
+    ExperimentStarted,
+    ExperimentUpdated,
+    ExperimentStopped,
+    Promoted,
+    ShadowStarted,
+    ShadowStopped,
+    Saved,
+}
+
+// Pushed to every connected client when a model's configuration changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelUpdate {
+    pub model_type: ModelType,
+    // The registered model that changed
+    pub model: String,
+    pub change: ModelChange,
+    pub details: serde_json::Value,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InferenceEvent {
+    pub model_type: ModelType,
+    pub model: String,
+    pub version: String,
+    pub prediction: serde_json::Value,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,12 +193,128 @@ pub struct WebSocketMessage {
     pub payload: serde_json::Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum MessageType {
     InferenceRequest,
     InferenceResponse,
+    BatchInferenceRequest,
+    BatchInferenceResponse,
     ModelUpdate,
     Error,
     Heartbeat,
-}
\ No newline at end of file
+    GetAnomalyThreshold,
+    SetAnomalyThreshold,
+    AnomalyThreshold,
+    Subscribe,
+    Unsubscribe,
+    Subscribed,
+    Unsubscribed,
+    SubscriptionUpdate,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ThresholdUpdate {
+    pub threshold: f32,
+    // Omit to update the global threshold
+    #[serde(default)]
+    pub sensor_type: Option<String>,
+}
+
+// WebSocket threshold messages name the anomaly model, REST routes take it from the path
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AnomalyModelRequest {
+    // Registered model name, the default anomaly model when omitted
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+impl AnomalyModelRequest {
+    pub fn name(&self) -> &str {
+        self.model.as_deref().unwrap_or(ModelType::AnomalyDetection.default_name())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnomalyThresholdRequest {
+    #[serde(flatten)]
+    pub target: AnomalyModelRequest,
+    #[serde(flatten)]
+    pub update: ThresholdUpdate,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnomalyThresholdReply {
+    pub model: String,
+    #[serde(flatten)]
+    pub config: anomaly::AnomalyThresholdConfig,
+}
+
+// With `data` the server runs the model on it at `rate_hz`, otherwise the stream
+// mirrors every result the model produces for any client. Anomaly models only mirror,
+// as they learn from every input.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    pub subscription_id: String,
+    #[serde(default)]
+    pub model_type: Option<ModelType>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+    #[serde(default)]
+    pub rate_hz: Option<f32>,
+    // Anomaly streams only: skip results that are not flagged as anomalies
+    #[serde(default)]
+    pub alerts_only: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnsubscribeRequest {
+    pub subscription_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscriptionUpdate {
+    pub subscription_id: String,
+    pub model_type: ModelType,
+    pub model: String,
+    #[serde(default)]
+    pub version: String,
+    pub prediction: serde_json::Value,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidMessage,
+    InvalidInput,
+    UnknownModel,
+    ModelUnavailable,
+    UnsupportedMessage,
+    InferenceFailed,
+    Internal,
+}
+
+// Payload of MessageType::Error, identifying the request that failed where known
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+    pub message_type: Option<MessageType>,
+    pub model_type: Option<ModelType>,
+    pub request_id: Option<String>,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            message_type: None,
+            model_type: None,
+            request_id: None,
+        }
+    }
+}
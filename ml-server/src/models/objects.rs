@@ -1,6 +1,24 @@
 use serde::{Deserialize, Serialize};
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use schemars::JsonSchema;
+use std::borrow::Cow;
 
+use super::{
+    frame::{EncodedImage, Image},
+    taxonomy::{ClassSpec, Taxonomy},
+    tracking::{TrackInfo, Tracker, TrackingParams},
+    ModelError,
+};
+use crate::ml::model::{fnv1a, Model};
+
+const MODEL_VERSION: &str = "0.1.0";
+// Simulated streams: objects move inside [-FIELD_HALF_WIDTH, FIELD_HALF_WIDTH) on both
+// axes at up to MAX_SIMULATED_SPEED units per second, with a little detection noise
+const FIELD_HALF_WIDTH: f64 = 50.0;
+const MAX_SIMULATED_SPEED: f64 = 10.0;
+const POSITION_JITTER: f32 = 0.25;
+
+// Top-left corner and size, in pixels of the input image for detectors that see one
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BoundingBox {
     pub x: f32,
@@ -9,54 +27,216 @@ pub struct BoundingBox {
     pub height: f32,
 }
 
+impl BoundingBox {
+    pub fn from_center(center_x: f32, center_y: f32, width: f32, height: f32) -> Self {
+        Self {
+            x: center_x - width / 2.0,
+            y: center_y - height / 2.0,
+            width,
+            height,
+        }
+    }
+
+    pub fn from_corners(left: f32, top: f32, right: f32, bottom: f32) -> Self {
+        Self {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        }
+    }
+
+    pub fn center(&self) -> (f32, f32) {
+        (self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+
+    // Left, top, right, bottom
+    pub fn corners(&self) -> (f32, f32, f32, f32) {
+        (self.x, self.y, self.x + self.width, self.y + self.height)
+    }
+
+    // Pixels to fractions of a frame_width x frame_height frame, and back
+    pub fn normalized(&self, frame_width: u32, frame_height: u32) -> Self {
+        let (frame_width, frame_height) = (frame_width as f32, frame_height as f32);
+        Self {
+            x: self.x / frame_width,
+            y: self.y / frame_height,
+            width: self.width / frame_width,
+            height: self.height / frame_height,
+        }
+    }
+
+    pub fn to_pixels(&self, frame_width: u32, frame_height: u32) -> Self {
+        let (frame_width, frame_height) = (frame_width as f32, frame_height as f32);
+        Self {
+            x: self.x * frame_width,
+            y: self.y * frame_height,
+            width: self.width * frame_width,
+            height: self.height * frame_height,
+        }
+    }
+
+    pub fn is_finite(&self) -> bool {
+        [self.x, self.y, self.width, self.height].iter().all(|value| value.is_finite())
+    }
+
+    pub fn area(&self) -> f32 {
+        self.width.max(0.0) * self.height.max(0.0)
+    }
+
+    pub fn iou(&self, other: &BoundingBox) -> f32 {
+        let (left, top, right, bottom) = self.corners();
+        let (other_left, other_top, other_right, other_bottom) = other.corners();
+        let overlap_w = (right.min(other_right) - left.max(other_left)).max(0.0);
+        let overlap_h = (bottom.min(other_bottom) - top.max(other_top)).max(0.0);
+        let intersection = overlap_w * overlap_h;
+        let union = self.area() + other.area() - intersection;
+        if union > 0.0 { intersection / union } else { 0.0 }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DetectedObject {
+    // A track id like track_3 when the frame belongs to a stream
     pub id: String,
     pub class_name: String,
     pub confidence: f32,
     pub bounding_box: BoundingBox,
+    #[serde(default)]
+    pub track: Option<TrackInfo>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct ObjectDetectionInput {
     pub frame_id: String,
     pub timestamp: i64,
-    // In production, this would contain actual image data
+    // Frames of one stream are tracked across calls, in timestamp order
+    #[serde(default)]
+    pub stream_id: Option<String>,
+    // At most one of image and encoded_image. Real detectors need one, the simulated
+    // one only checks it.
+    #[serde(default)]
+    pub image: Option<Image>,
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub encoded_image: Option<EncodedImage>,
+    #[serde(default)]
     pub simulate_complex: bool,
+    // Overrides the detector's own post-processing settings
+    #[serde(default)]
+    pub postprocess: PostProcessing,
+}
+
+impl ObjectDetectionInput {
+    // The frame as raw RGB, decoded if it arrived as a file
+    pub fn frame(&self) -> Result<Option<Cow<'_, Image>>, ModelError> {
+        match (&self.image, &self.encoded_image) {
+            (Some(_), Some(_)) => Err(ModelError::InvalidInput(
+                "send either image or encoded_image, not both".to_string(),
+            )),
+            (Some(image), None) => {
+                image.validate()?;
+                Ok(Some(Cow::Borrowed(image)))
+            }
+            (None, Some(encoded)) => Ok(Some(Cow::Owned(encoded.decode()?))),
+            (None, None) => Ok(None),
+        }
+    }
 }
 
+// Timing is left to the response's latency_ms, so a seeded prediction is the same every time
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ObjectDetectionOutput {
     pub frame_id: String,
     pub objects: Vec<DetectedObject>,
-    pub processing_time_ms: f32,
+    // Tracks of the stream that went undetected for too long and were dropped
+    #[serde(default)]
+    pub retired_tracks: Vec<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObjectParams {
+    // Replaces the built-in label map, names or {id, name, color, category} objects
+    pub object_classes: Option<Vec<ClassSpec>>,
+    pub tracking: TrackingParams,
 }
 
 pub struct ObjectDetector {
-    object_classes: Vec<String>,
+    taxonomy: Taxonomy,
+    tracker: Tracker,
 }
 
 impl ObjectDetector {
+    pub fn with_params(params: ObjectParams) -> Result<Self, ModelError> {
+        let taxonomy = match params.object_classes {
+            Some(object_classes) => Taxonomy::new(object_classes)?,
+            None => Taxonomy::builtin(),
+        };
+        Ok(Self {
+            taxonomy,
+            tracker: Tracker::new(params.tracking)?,
+        })
+    }
+
     pub fn new() -> Self {
         Self {
-            object_classes: vec![
-                "car".to_string(),
-                "truck".to_string(),
-                "pedestrian".to_string(),
-                "bicycle".to_string(),
-                "motorcycle".to_string(),
-                "bus".to_string(),
-                "traffic_light".to_string(),
-                "stop_sign".to_string(),
-            ],
-        }
-    }
-
-    pub fn detect(&self, input: &ObjectDetectionInput) -> ObjectDetectionOutput {
-        let mut rng = rand::thread_rng();
-        let start = std::time::Instant::now();
-        
-        // Simulate object detection
+            taxonomy: Taxonomy::builtin(),
+            tracker: Tracker::default(),
+        }
+    }
+
+    pub fn taxonomy(&self) -> &Taxonomy {
+        &self.taxonomy
+    }
+
+    pub fn detect<R: Rng>(&self, input: &ObjectDetectionInput, rng: &mut R) -> ObjectDetectionOutput {
+        let objects = match &input.stream_id {
+            Some(stream) => self.stream_scene(stream, input, rng),
+            None => self.random_scene(input, rng),
+        };
+        ObjectDetectionOutput {
+            frame_id: input.frame_id.clone(),
+            objects,
+            retired_tracks: Vec::new(),
+        }
+    }
+
+    // A stream shows the same objects in every frame, each at constant velocity and bouncing
+    // off the edges of the field, so its tracks last. The scene follows from the stream id
+    // and the timestamp, the request's rng only adds noise.
+    fn stream_scene<R: Rng>(&self, stream: &str, input: &ObjectDetectionInput, rng: &mut R) -> Vec<DetectedObject> {
+        let mut scene = StdRng::seed_from_u64(fnv1a(stream.as_bytes()));
+        let num_objects = if input.simulate_complex {
+            scene.gen_range(5..15)
+        } else {
+            scene.gen_range(2..8)
+        };
+        let seconds = input.timestamp as f64 / 1000.0;
+        let labels = self.taxonomy.labels();
+        (0..num_objects)
+            .map(|i| {
+                let class_name = labels[scene.gen_range(0..labels.len())].name.clone();
+                let (width, height) = (scene.gen_range(5.0..20.0), scene.gen_range(5.0..20.0));
+                let mut position = || {
+                    let start = scene.gen_range(-FIELD_HALF_WIDTH..FIELD_HALF_WIDTH);
+                    let velocity = scene.gen_range(-MAX_SIMULATED_SPEED..MAX_SIMULATED_SPEED);
+                    bounce(start, velocity, seconds) + rng.gen_range(-POSITION_JITTER..POSITION_JITTER)
+                };
+                let (x, y) = (position(), position());
+                DetectedObject {
+                    id: format!("obj_{}", i),
+                    class_name,
+                    confidence: 0.7 + rng.gen::<f32>() * 0.3,
+                    bounding_box: BoundingBox { x, y, width, height },
+                    track: None,
+                }
+            })
+            .collect()
+    }
+
+    // Frames outside a stream are unrelated, every object is drawn anew
+    fn random_scene<R: Rng>(&self, input: &ObjectDetectionInput, rng: &mut R) -> Vec<DetectedObject> {
         let num_objects = if input.simulate_complex {
             rng.gen_range(5..15)
         } else {
@@ -65,10 +245,10 @@ impl ObjectDetector {
         
         let mut objects = Vec::new();
         for i in 0..num_objects {
-            let class_idx = rng.gen_range(0..self.object_classes.len());
+            let labels = self.taxonomy.labels();
             objects.push(DetectedObject {
                 id: format!("obj_{}", i),
-                class_name: self.object_classes[class_idx].clone(),
+                class_name: labels[rng.gen_range(0..labels.len())].name.clone(),
                 confidence: 0.7 + rng.gen::<f32>() * 0.3,
                 bounding_box: BoundingBox {
                     x: rng.gen_range(-50.0..50.0),
@@ -76,13 +256,297 @@ impl ObjectDetector {
                     width: rng.gen_range(5.0..20.0),
                     height: rng.gen_range(5.0..20.0),
                 },
+                track: None,
             });
         }
-        
-        ObjectDetectionOutput {
-            frame_id: input.frame_id.clone(),
-            objects,
-            processing_time_ms: start.elapsed().as_secs_f32() * 1000.0,
+        objects
+    }
+}
+
+// Where a point moving from `start` at `velocity` is after `seconds`, reflected at the
+// edges of the field
+fn bounce(start: f64, velocity: f64, seconds: f64) -> f32 {
+    let width = 2.0 * FIELD_HALF_WIDTH;
+    let travelled = (start + FIELD_HALF_WIDTH + velocity * seconds).rem_euclid(2.0 * width);
+    let offset = if travelled < width { travelled } else { 2.0 * width - travelled };
+    (offset - FIELD_HALF_WIDTH) as f32
+}
+
+// Applied to every detector's raw objects. Unset fields fall back to the detector's
+// defaults, unset there means that step is skipped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct PostProcessing {
+    // Drops objects less confident than this
+    pub score_threshold: Option<f32>,
+    // Non-maximum suppression of boxes overlapping more than this
+    pub iou_threshold: Option<f32>,
+    // Lets objects of different classes suppress each other
+    pub class_agnostic: Option<bool>,
+    // Keeps only the most confident objects
+    pub max_detections: Option<usize>,
+    // Boxes as fractions of the frame instead of pixels, needs a frame
+    pub normalized: Option<bool>,
+    // Keeps only these classes, and only classes in these categories
+    pub classes: Option<Vec<String>>,
+    pub categories: Option<Vec<String>>,
+}
+
+impl PostProcessing {
+    pub fn validate(&self) -> Result<(), ModelError> {
+        for (name, value) in [("score_threshold", self.score_threshold), ("iou_threshold", self.iou_threshold)] {
+            if let Some(value) = value.filter(|value| !(0.0..=1.0).contains(value)) {
+                return Err(ModelError::InvalidInput(format!("{} must be between 0 and 1, got {}", name, value)));
+            }
         }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn or(&self, defaults: &PostProcessing) -> PostProcessing {
+        PostProcessing {
+            score_threshold: self.score_threshold.or(defaults.score_threshold),
+            iou_threshold: self.iou_threshold.or(defaults.iou_threshold),
+            class_agnostic: self.class_agnostic.or(defaults.class_agnostic),
+            max_detections: self.max_detections.or(defaults.max_detections),
+            normalized: self.normalized.or(defaults.normalized),
+            classes: self.classes.clone().or_else(|| defaults.classes.clone()),
+            categories: self.categories.clone().or_else(|| defaults.categories.clone()),
+        }
+    }
+
+    // Objects come back most confident first. frame_size is the width and height boxes
+    // are measured in, the taxonomy is the detector's own.
+    pub fn apply(
+        &self,
+        mut objects: Vec<DetectedObject>,
+        frame_size: Option<(u32, u32)>,
+        taxonomy: &Taxonomy,
+    ) -> Result<Vec<DetectedObject>, ModelError> {
+        self.validate()?;
+        taxonomy.check_filter(self.classes.as_deref(), self.categories.as_deref())?;
+        if let Some(threshold) = self.score_threshold {
+            objects.retain(|object| object.confidence >= threshold);
+        }
+        // Before suppression, so an unwanted class never suppresses a wanted one
+        if let Some(classes) = &self.classes {
+            objects.retain(|object| classes.contains(&object.class_name));
+        }
+        if let Some(categories) = &self.categories {
+            objects.retain(|object| {
+                taxonomy
+                    .category_of(&object.class_name)
+                    .is_some_and(|category| categories.iter().any(|wanted| wanted == category))
+            });
+        }
+        objects = match self.iou_threshold {
+            Some(threshold) => non_max_suppression(objects, threshold, self.class_agnostic.unwrap_or(false)),
+            None => sort_by_confidence(objects),
+        };
+        if let Some(max_detections) = self.max_detections {
+            objects.truncate(max_detections);
+        }
+        if self.normalized.unwrap_or(false) {
+            let (width, height) = frame_size.ok_or_else(|| {
+                ModelError::InvalidInput("normalized boxes need an image to measure against".to_string())
+            })?;
+            for object in &mut objects {
+                object.bounding_box = object.bounding_box.normalized(width, height);
+            }
+        }
+        Ok(objects)
+    }
+}
+
+fn sort_by_confidence(mut objects: Vec<DetectedObject>) -> Vec<DetectedObject> {
+    objects.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    objects
+}
+
+// Greedy, most confident first. Unless class agnostic, only objects of the same class
+// suppress each other.
+pub fn non_max_suppression(
+    objects: Vec<DetectedObject>,
+    iou_threshold: f32,
+    class_agnostic: bool,
+) -> Vec<DetectedObject> {
+    let mut kept: Vec<DetectedObject> = Vec::new();
+    for object in sort_by_confidence(objects) {
+        let suppressed = kept.iter().any(|other| {
+            (class_agnostic || other.class_name == object.class_name)
+                && other.bounding_box.iou(&object.bounding_box) > iou_threshold
+        });
+        if !suppressed {
+            kept.push(object);
+        }
+    }
+    kept
+}
+
+impl Model for ObjectDetector {
+    type Input = ObjectDetectionInput;
+    type Output = ObjectDetectionOutput;
+    type Params = ObjectParams;
+
+    fn name(&self) -> &'static str {
+        "simulated_detector"
+    }
+
+    fn version(&self) -> String {
+        MODEL_VERSION.to_string()
+    }
+
+    fn params(&self) -> Self::Params {
+        ObjectParams {
+            object_classes: Some(self.taxonomy.specs()),
+            tracking: self.tracker.params().clone(),
+        }
+    }
+
+    fn predict(&self, input: &Self::Input, rng: &mut StdRng) -> anyhow::Result<Self::Output> {
+        let frame = input.frame()?;
+        let mut output = self.detect(input, rng);
+        output.objects = input.postprocess.apply(
+            output.objects,
+            frame.map(|frame| (frame.width, frame.height)),
+            &self.taxonomy,
+        )?;
+        if let Some(stream) = &input.stream_id {
+            output.retired_tracks = self.tracker.track(stream, input.timestamp, &mut output.objects)?;
+        }
+        Ok(output)
+    }
+}
+
+impl Default for ObjectDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbox(x: f32, y: f32, width: f32, height: f32) -> BoundingBox {
+        BoundingBox { x, y, width, height }
+    }
+
+    fn object(class_name: &str, confidence: f32, x: f32) -> DetectedObject {
+        DetectedObject {
+            id: String::new(),
+            class_name: class_name.to_string(),
+            confidence,
+            bounding_box: bbox(x, 0.0, 10.0, 10.0),
+            track: None,
+        }
+    }
+
+    fn ranked(objects: &[DetectedObject]) -> Vec<(&str, f32)> {
+        objects.iter().map(|object| (object.class_name.as_str(), object.confidence)).collect()
+    }
+
+    #[test]
+    fn iou_of_identical_boxes_is_one() {
+        let a = bbox(5.0, 5.0, 20.0, 10.0);
+        assert_eq!(a.iou(&a.clone()), 1.0);
+    }
+
+    #[test]
+    fn iou_of_disjoint_or_touching_boxes_is_zero() {
+        let a = bbox(0.0, 0.0, 10.0, 10.0);
+        assert_eq!(a.iou(&bbox(50.0, 50.0, 10.0, 10.0)), 0.0);
+        assert_eq!(a.iou(&bbox(10.0, 0.0, 10.0, 10.0)), 0.0);
+    }
+
+    #[test]
+    fn iou_with_zero_area_boxes_is_zero() {
+        let point = bbox(5.0, 5.0, 0.0, 0.0);
+        assert_eq!(point.iou(&point.clone()), 0.0);
+        assert_eq!(point.iou(&bbox(0.0, 0.0, 10.0, 10.0)), 0.0);
+        assert_eq!(bbox(0.0, 0.0, 10.0, 0.0).iou(&bbox(0.0, 0.0, 10.0, 10.0)), 0.0);
+    }
+
+    #[test]
+    fn iou_of_partial_overlap() {
+        // 5x10 shared out of 150
+        let iou = bbox(0.0, 0.0, 10.0, 10.0).iou(&bbox(5.0, 0.0, 10.0, 10.0));
+        assert!((iou - 50.0 / 150.0).abs() < 1e-6, "{}", iou);
+    }
+
+    #[test]
+    fn nms_keeps_the_most_confident_of_a_class() {
+        let kept = non_max_suppression(vec![object("car", 0.6, 1.0), object("car", 0.9, 0.0)], 0.5, false);
+        assert_eq!(ranked(&kept), vec![("car", 0.9)]);
+    }
+
+    #[test]
+    fn nms_keeps_overlapping_objects_of_other_classes() {
+        let kept = non_max_suppression(vec![object("car", 0.9, 0.0), object("person", 0.6, 1.0)], 0.5, false);
+        assert_eq!(ranked(&kept), vec![("car", 0.9), ("person", 0.6)]);
+    }
+
+    #[test]
+    fn class_agnostic_nms_suppresses_across_classes() {
+        let kept = non_max_suppression(vec![object("car", 0.9, 0.0), object("person", 0.6, 1.0)], 0.5, true);
+        assert_eq!(ranked(&kept), vec![("car", 0.9)]);
+    }
+
+    #[test]
+    fn nms_keeps_objects_below_the_iou_threshold() {
+        // IoU 1/3 between the two
+        let kept = non_max_suppression(vec![object("car", 0.9, 0.0), object("car", 0.6, 5.0)], 0.5, false);
+        assert_eq!(kept.len(), 2);
+    }
+
+    fn frame(stream: &str, timestamp: i64) -> ObjectDetectionInput {
+        ObjectDetectionInput {
+            frame_id: format!("{}-{}", stream, timestamp),
+            timestamp,
+            stream_id: Some(stream.to_string()),
+            image: None,
+            encoded_image: None,
+            simulate_complex: false,
+            postprocess: PostProcessing::default(),
+        }
+    }
+
+    #[test]
+    fn simulated_streams_keep_their_tracks() {
+        let detector = ObjectDetector::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        let frames: Vec<_> = (0..5)
+            .map(|i| detector.predict(&frame("cam", i * 100), &mut rng).unwrap())
+            .collect();
+        let last = frames.last().unwrap();
+        assert_eq!(last.objects.len(), frames[0].objects.len());
+        assert!(last.retired_tracks.is_empty());
+        for object in &last.objects {
+            let track = object.track.as_ref().unwrap();
+            assert_eq!((track.age, track.hits, track.age_ms), (5, 5, 400), "{:?}", object);
+        }
+    }
+
+    #[test]
+    fn streams_show_different_scenes() {
+        let detector = ObjectDetector::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        let boxes = |output: ObjectDetectionOutput| -> Vec<(f32, f32)> {
+            output.objects.iter().map(|object| (object.bounding_box.width, object.bounding_box.height)).collect()
+        };
+        let a = boxes(detector.detect(&frame("a", 0), &mut rng));
+        assert_eq!(a, boxes(detector.detect(&frame("a", 1000), &mut rng)));
+        assert_ne!(a, boxes(detector.detect(&frame("b", 0), &mut rng)));
+    }
+
+    #[test]
+    fn simulated_objects_bounce_inside_the_field() {
+        assert_eq!(bounce(0.0, 10.0, 2.0), 20.0);
+        // Reflected off the right edge at 50
+        assert_eq!(bounce(40.0, 10.0, 2.0), 40.0);
+        assert_eq!(bounce(-40.0, -10.0, 2.0), -40.0);
+        for seconds in [-1e6, -3.3, 0.0, 7.1, 1e9] {
+            let x = bounce(12.0, 9.5, seconds);
+            assert!((-50.0..=50.0).contains(&x), "{} at {}", x, seconds);
+        }
+    }
+}
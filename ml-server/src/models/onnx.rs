@@ -0,0 +1,215 @@
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tract_onnx::prelude::*;
+
+use super::frame::{Image, Letterbox};
+use super::objects::{BoundingBox, DetectedObject, ObjectDetectionInput, ObjectDetectionOutput, PostProcessing};
+use super::taxonomy::{ClassSpec, Taxonomy};
+use super::tracking::{Tracker, TrackingParams};
+use super::ModelError;
+use crate::ml::{artifact::resolve_path, model::Model};
+
+const MODEL_VERSION: &str = "0.1.0";
+
+// How the detector lays out its single output tensor
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputLayout {
+    // [1, 4 + classes, boxes], no objectness
+    #[default]
+    Yolov8,
+    // [1, boxes, 5 + classes], objectness after the box
+    Yolov5,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OnnxParams {
+    pub model_path: PathBuf,
+    // Label map in the order of the model's outputs, names or {id, name, color, category}
+    pub classes: Vec<ClassSpec>,
+    #[serde(default = "default_input_size")]
+    pub input_width: u32,
+    #[serde(default = "default_input_size")]
+    pub input_height: u32,
+    #[serde(default)]
+    pub layout: OutputLayout,
+    #[serde(default = "default_confidence_threshold")]
+    pub confidence_threshold: f32,
+    #[serde(default = "default_iou_threshold")]
+    pub iou_threshold: f32,
+    #[serde(default)]
+    pub tracking: TrackingParams,
+}
+
+fn default_input_size() -> u32 {
+    640
+}
+
+fn default_confidence_threshold() -> f32 {
+    0.25
+}
+
+fn default_iou_threshold() -> f32 {
+    0.45
+}
+
+// Saved params name the backend, so an artifact rebuilds the same kind of detector
+#[derive(Serialize)]
+pub struct SavedOnnxParams {
+    backend: &'static str,
+    #[serde(flatten)]
+    params: OnnxParams,
+}
+
+type Plan = TypedRunnableModel<TypedModel>;
+
+// Runs a YOLO style detector on the CPU
+pub struct OnnxDetector {
+    params: OnnxParams,
+    plan: Plan,
+    taxonomy: Taxonomy,
+    tracker: Tracker,
+}
+
+impl OnnxDetector {
+    // model_path is relative to the model directory and stays that way in params
+    pub fn with_params(params: OnnxParams, model_dir: Option<&Path>) -> Result<Self, ModelError> {
+        if params.input_width == 0 || params.input_height == 0 {
+            return Err(ModelError::InvalidInput("an onnx detector needs a non-zero input size".to_string()));
+        }
+        let taxonomy = Taxonomy::new(params.classes.clone())?;
+        if !(0.0..=1.0).contains(&params.confidence_threshold) || !(0.0..=1.0).contains(&params.iou_threshold) {
+            return Err(ModelError::InvalidInput("thresholds must be between 0 and 1".to_string()));
+        }
+        let tracker = Tracker::new(params.tracking.clone())?;
+        let model_path = resolve_path(model_dir, &params.model_path)?;
+        let plan = Self::load(&params, &model_path).map_err(|e| {
+            ModelError::InvalidInput(format!("cannot load onnx model {}: {}", params.model_path.display(), e))
+        })?;
+        Ok(Self { params, plan, taxonomy, tracker })
+    }
+
+    fn load(params: &OnnxParams, model_path: &Path) -> TractResult<Plan> {
+        let shape = [1, 3, params.input_height as usize, params.input_width as usize];
+        tract_onnx::onnx()
+            .model_for_path(model_path)?
+            .with_input_fact(0, f32::fact(shape).into())?
+            .into_optimized()?
+            .into_runnable()
+    }
+
+    // Scaled to 0..1, NCHW. The image is already the model's input size.
+    fn to_tensor(image: &Image) -> Tensor {
+        let width = image.width as usize;
+        tract_ndarray::Array4::from_shape_fn((1, 3, image.height as usize, width), |(_, channel, y, x)| {
+            image.rgb[(y * width + x) * 3 + channel] as f32 / 255.0
+        })
+        .into()
+    }
+
+    pub fn taxonomy(&self) -> &Taxonomy {
+        &self.taxonomy
+    }
+
+    // Settings a request does not override
+    fn postprocess_defaults(&self) -> PostProcessing {
+        PostProcessing {
+            score_threshold: Some(self.params.confidence_threshold),
+            iou_threshold: Some(self.params.iou_threshold),
+            ..Default::default()
+        }
+    }
+
+    // Most rows are background, dropping those below score_threshold here keeps NMS cheap
+    fn decode(&self, output: &Tensor, letterbox: &Letterbox, score_threshold: f32) -> anyhow::Result<Vec<DetectedObject>> {
+        let output = output.to_array_view::<f32>()?;
+        let labels = self.taxonomy.labels();
+        let classes = labels.len();
+        let (rows, fields, extra) = match (self.params.layout, output.shape()) {
+            (OutputLayout::Yolov8, &[1, fields, rows]) => (rows, fields, 4),
+            (OutputLayout::Yolov5, &[1, rows, fields]) => (rows, fields, 5),
+            (_, shape) => anyhow::bail!("unexpected detector output shape {:?}", shape),
+        };
+        if fields != extra + classes {
+            anyhow::bail!("detector outputs {} fields per box, {} classes need {}", fields, classes, extra + classes);
+        }
+        let value = |row: usize, field: usize| match self.params.layout {
+            OutputLayout::Yolov8 => output[[0, field, row]],
+            OutputLayout::Yolov5 => output[[0, row, field]],
+        };
+
+        let mut objects = Vec::new();
+        for row in 0..rows {
+            let objectness = match self.params.layout {
+                OutputLayout::Yolov8 => 1.0,
+                OutputLayout::Yolov5 => value(row, 4),
+            };
+            let (class, score) = (0..classes)
+                .map(|class| (class, value(row, extra + class)))
+                .fold((0, f32::MIN), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+            let confidence = score * objectness;
+            if confidence < score_threshold {
+                continue;
+            }
+            let (center_x, center_y, width, height) = (value(row, 0), value(row, 1), value(row, 2), value(row, 3));
+            objects.push(DetectedObject {
+                id: String::new(),
+                class_name: labels[class].name.clone(),
+                confidence,
+                bounding_box: letterbox.to_source(&BoundingBox::from_center(center_x, center_y, width, height)),
+                track: None,
+            });
+        }
+
+        Ok(objects)
+    }
+}
+
+impl Model for OnnxDetector {
+    type Input = ObjectDetectionInput;
+    type Output = ObjectDetectionOutput;
+    type Params = SavedOnnxParams;
+
+    fn name(&self) -> &'static str {
+        "onnx_detector"
+    }
+
+    fn version(&self) -> String {
+        MODEL_VERSION.to_string()
+    }
+
+    fn params(&self) -> Self::Params {
+        SavedOnnxParams {
+            backend: "onnx",
+            params: self.params.clone(),
+        }
+    }
+
+    fn predict(&self, input: &Self::Input, _rng: &mut StdRng) -> anyhow::Result<Self::Output> {
+        let image = input
+            .frame()?
+            .ok_or_else(|| ModelError::InvalidInput("the onnx detector needs an image or encoded_image".to_string()))?;
+        let postprocess = input.postprocess.or(&self.postprocess_defaults());
+        postprocess.validate()?;
+        let letterbox = image.letterbox(self.params.input_width, self.params.input_height);
+
+        let outputs = self.plan.run(tvec!(Self::to_tensor(&letterbox.image).into()))?;
+        let objects = self.decode(&outputs[0], &letterbox, postprocess.score_threshold.unwrap_or(0.0))?;
+        let mut objects = postprocess.apply(objects, Some((image.width, image.height)), &self.taxonomy)?;
+        for (i, object) in objects.iter_mut().enumerate() {
+            object.id = format!("obj_{}", i);
+        }
+        let retired_tracks = match &input.stream_id {
+            Some(stream) => self.tracker.track(stream, input.timestamp, &mut objects)?,
+            None => Vec::new(),
+        };
+
+        Ok(ObjectDetectionOutput {
+            frame_id: input.frame_id.clone(),
+            objects,
+            retired_tracks,
+        })
+    }
+}
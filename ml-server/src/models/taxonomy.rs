@@ -0,0 +1,326 @@
+use serde::{
+    de::{self, MapAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use super::ModelError;
+
+// id, name, color, category. Colors follow the dashboard's detection chart.
+const BUILTIN_CLASSES: [(u32, &str, &str, &str); 8] = [
+    (0, "car", "#00ff00", "vehicle"),
+    (1, "truck", "#00c000", "vehicle"),
+    (2, "pedestrian", "#ffff00", "vulnerable_road_user"),
+    (3, "bicycle", "#00ffff", "vulnerable_road_user"),
+    (4, "motorcycle", "#0080ff", "vulnerable_road_user"),
+    (5, "bus", "#008000", "vehicle"),
+    (6, "traffic_light", "#ff8000", "traffic_control"),
+    (7, "stop_sign", "#ff00ff", "traffic_control"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClassLabel {
+    pub id: u32,
+    pub name: String,
+    // #rrggbb, for drawing boxes of this class
+    pub color: Option<String>,
+    // Parent grouping such as "vehicle", requests can filter on it
+    pub category: Option<String>,
+}
+
+// One class of a detector's params. Either just a name or a label object, entries
+// without an id are numbered by their position.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClassSpec {
+    pub id: Option<u32>,
+    pub name: String,
+    pub color: Option<String>,
+    pub category: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClassSpecFields {
+    #[serde(default)]
+    id: Option<u32>,
+    name: String,
+    #[serde(default)]
+    color: Option<String>,
+    #[serde(default)]
+    category: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for ClassSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SpecVisitor;
+
+        impl<'de> Visitor<'de> for SpecVisitor {
+            type Value = ClassSpec;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a class name or an object with id, name, color and category")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<ClassSpec, E> {
+                Ok(ClassSpec {
+                    id: None,
+                    name: name.to_string(),
+                    color: None,
+                    category: None,
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ClassSpec, A::Error> {
+                let fields = ClassSpecFields::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(ClassSpec {
+                    id: fields.id,
+                    name: fields.name,
+                    color: fields.color,
+                    category: fields.category,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(SpecVisitor)
+    }
+}
+
+// A detector's classes in output order
+#[derive(Debug, Clone)]
+pub struct Taxonomy {
+    labels: Vec<ClassLabel>,
+}
+
+// What the classes endpoint returns
+#[derive(Debug, Serialize)]
+pub struct ClassList {
+    pub model: String,
+    pub classes: Vec<ClassLabel>,
+    // Class names under each category
+    pub categories: BTreeMap<String, Vec<String>>,
+}
+
+impl Taxonomy {
+    pub fn new(specs: Vec<ClassSpec>) -> Result<Self, ModelError> {
+        if specs.is_empty() {
+            return Err(ModelError::InvalidInput("the class list is empty".to_string()));
+        }
+        let mut ids = HashSet::new();
+        let mut names = HashSet::new();
+        let mut labels = Vec::with_capacity(specs.len());
+        for (position, spec) in specs.into_iter().enumerate() {
+            let id = spec.id.unwrap_or(position as u32);
+            if spec.name.is_empty() || !names.insert(spec.name.clone()) {
+                return Err(ModelError::InvalidInput(format!(
+                    "class names must be non-empty and unique, got {:?}",
+                    spec.name
+                )));
+            }
+            if !ids.insert(id) {
+                return Err(ModelError::InvalidInput(format!("class id {} is used twice", id)));
+            }
+            if let Some(color) = spec.color.as_deref().filter(|color| !is_hex_color(color)) {
+                return Err(ModelError::InvalidInput(format!(
+                    "color of class {} must look like #rrggbb, got {:?}",
+                    spec.name, color
+                )));
+            }
+            if spec.category.as_deref().is_some_and(str::is_empty) {
+                return Err(ModelError::InvalidInput(format!("category of class {} is empty", spec.name)));
+            }
+            labels.push(ClassLabel {
+                id,
+                name: spec.name,
+                color: spec.color,
+                category: spec.category,
+            });
+        }
+        Ok(Self { labels })
+    }
+
+    pub fn builtin() -> Self {
+        Self {
+            labels: BUILTIN_CLASSES
+                .iter()
+                .map(|&(id, name, color, category)| ClassLabel {
+                    id,
+                    name: name.to_string(),
+                    color: Some(color.to_string()),
+                    category: Some(category.to_string()),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn labels(&self) -> &[ClassLabel] {
+        &self.labels
+    }
+
+    // As params, so a saved detector rebuilds with the same ids
+    pub fn specs(&self) -> Vec<ClassSpec> {
+        self.labels
+            .iter()
+            .map(|label| ClassSpec {
+                id: Some(label.id),
+                name: label.name.clone(),
+                color: label.color.clone(),
+                category: label.category.clone(),
+            })
+            .collect()
+    }
+
+    pub fn category_of(&self, class_name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|label| label.name == class_name)
+            .and_then(|label| label.category.as_deref())
+    }
+
+    // Fails on names the taxonomy does not have, so a typo does not silently filter everything out
+    pub fn check_filter(&self, classes: Option<&[String]>, categories: Option<&[String]>) -> Result<(), ModelError> {
+        for class in classes.unwrap_or_default() {
+            if !self.labels.iter().any(|label| label.name == *class) {
+                return Err(ModelError::InvalidInput(format!(
+                    "unknown class {:?}, this detector has {}",
+                    class,
+                    self.labels.iter().map(|label| label.name.as_str()).collect::<Vec<_>>().join(", ")
+                )));
+            }
+        }
+        let known = self.categories();
+        for category in categories.unwrap_or_default() {
+            if !known.contains_key(category) {
+                return Err(ModelError::InvalidInput(format!(
+                    "unknown category {:?}, this detector has {}",
+                    category,
+                    known.keys().cloned().collect::<Vec<_>>().join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn categories(&self) -> BTreeMap<String, Vec<String>> {
+        let mut categories: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for label in &self.labels {
+            if let Some(category) = &label.category {
+                categories.entry(category.clone()).or_default().push(label.name.clone());
+            }
+        }
+        categories
+    }
+
+    pub fn describe(&self, model: &str) -> ClassList {
+        ClassList {
+            model: model.to_string(),
+            classes: self.labels.clone(),
+            categories: self.categories(),
+        }
+    }
+}
+
+fn is_hex_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn taxonomy(classes: serde_json::Value) -> Result<Taxonomy, ModelError> {
+        Taxonomy::new(serde_json::from_value(classes).unwrap())
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn names_and_objects_mix_in_one_list() {
+        let taxonomy = taxonomy(json!([
+            "car",
+            {"name": "person", "color": "#ff0000", "category": "vulnerable_road_user"},
+            {"id": 10, "name": "sign"},
+        ]))
+        .unwrap();
+        let labels: Vec<_> = taxonomy
+            .labels()
+            .iter()
+            .map(|label| (label.id, label.name.as_str(), label.color.as_deref(), label.category.as_deref()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                (0, "car", None, None),
+                (1, "person", Some("#ff0000"), Some("vulnerable_road_user")),
+                (10, "sign", None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_specs_do_not_parse() {
+        for spec in [json!(3), json!({"id": 1}), json!({"name": "car", "colour": "#ff0000"})] {
+            assert!(serde_json::from_value::<ClassSpec>(spec.clone()).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn duplicate_ids_are_rejected() {
+        // The second entry takes id 1 from its position
+        let error = taxonomy(json!([{"id": 1, "name": "car"}, "person"])).unwrap_err();
+        assert!(error.to_string().contains("class id 1 is used twice"), "{}", error);
+    }
+
+    #[test]
+    fn duplicate_and_empty_names_are_rejected() {
+        assert!(taxonomy(json!(["car", {"id": 5, "name": "car"}])).is_err());
+        assert!(taxonomy(json!([""])).is_err());
+        assert!(taxonomy(json!([])).is_err());
+    }
+
+    #[test]
+    fn colors_and_categories_are_checked() {
+        assert!(taxonomy(json!([{"name": "car", "color": "green"}])).is_err());
+        assert!(taxonomy(json!([{"name": "car", "color": "#00ff0"}])).is_err());
+        assert!(taxonomy(json!([{"name": "car", "category": ""}])).is_err());
+    }
+
+    #[test]
+    fn specs_rebuild_the_same_taxonomy() {
+        let builtin = Taxonomy::builtin();
+        let rebuilt = Taxonomy::new(builtin.specs()).unwrap();
+        assert_eq!(
+            serde_json::to_value(rebuilt.labels()).unwrap(),
+            serde_json::to_value(builtin.labels()).unwrap()
+        );
+    }
+
+    #[test]
+    fn filters_on_known_names_pass() {
+        let builtin = Taxonomy::builtin();
+        assert!(builtin.check_filter(None, None).is_ok());
+        assert!(builtin
+            .check_filter(Some(&strings(&["car", "bus"])), Some(&strings(&["traffic_control"])))
+            .is_ok());
+    }
+
+    #[test]
+    fn filters_on_unknown_names_are_rejected() {
+        let builtin = Taxonomy::builtin();
+        let error = builtin.check_filter(Some(&strings(&["car", "cars"])), None).unwrap_err();
+        assert!(error.to_string().contains("unknown class \"cars\""), "{}", error);
+        let error = builtin.check_filter(None, Some(&strings(&["vehicles"]))).unwrap_err();
+        assert!(error.to_string().contains("unknown category \"vehicles\""), "{}", error);
+    }
+
+    #[test]
+    fn categories_list_their_classes_in_order() {
+        let categories = Taxonomy::builtin().categories();
+        assert_eq!(categories["vehicle"], strings(&["car", "truck", "bus"]));
+        assert_eq!(Taxonomy::builtin().category_of("stop_sign"), Some("traffic_control"));
+        assert_eq!(Taxonomy::builtin().category_of("unicorn"), None);
+    }
+}
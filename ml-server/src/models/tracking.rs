@@ -0,0 +1,457 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use super::{
+    objects::{BoundingBox, DetectedObject},
+    ModelError,
+};
+
+// Streams not seen for this long are dropped once the stream limit is reached
+const MAX_STREAMS: usize = 1024;
+const STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Filter tuning, relative to the box size so pixel and normalized boxes behave the same.
+// Time is in seconds.
+const MEASUREMENT_NOISE_RATIO: f64 = 0.05;
+const ACCELERATION_NOISE_RATIO: f64 = 1.0;
+const INITIAL_VELOCITY_RATIO: f64 = 10.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrackingParams {
+    // Least overlap between a track's predicted box and a detection for them to match
+    pub iou_threshold: f32,
+    // Frames a track may go undetected before it is retired
+    pub max_age: u32,
+}
+
+impl Default for TrackingParams {
+    fn default() -> Self {
+        Self {
+            iou_threshold: 0.3,
+            max_age: 3,
+        }
+    }
+}
+
+impl TrackingParams {
+    pub fn validate(&self) -> Result<(), ModelError> {
+        if !(self.iou_threshold > 0.0 && self.iou_threshold <= 1.0) {
+            return Err(ModelError::InvalidInput(format!(
+                "tracking iou_threshold must be above 0 and at most 1, got {}",
+                self.iou_threshold
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackInfo {
+    pub track_id: u64,
+    // Frames since the track started and the time they span
+    pub age: u32,
+    pub age_ms: i64,
+    // Frames the object was detected in
+    pub hits: u32,
+    // Of the box center, in box units per second
+    pub velocity_x: f32,
+    pub velocity_y: f32,
+}
+
+// Constant velocity filter for one box coordinate. The four coordinates move
+// independently, so four of these are exactly one filter over the whole box.
+#[derive(Clone)]
+struct AxisFilter {
+    value: f64,
+    rate: f64,
+    covariance: [[f64; 2]; 2],
+}
+
+impl AxisFilter {
+    fn new(value: f64, scale: f64) -> Self {
+        Self {
+            value,
+            rate: 0.0,
+            covariance: [
+                [(MEASUREMENT_NOISE_RATIO * scale).powi(2), 0.0],
+                [0.0, (INITIAL_VELOCITY_RATIO * scale).powi(2)],
+            ],
+        }
+    }
+
+    // Discretised white noise acceleration
+    fn predict(&mut self, dt: f64, scale: f64) {
+        let q = (ACCELERATION_NOISE_RATIO * scale).powi(2);
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        self.value += self.rate * dt;
+        self.covariance = [
+            [
+                p00 + dt * (p10 + p01) + dt * dt * p11 + q * dt.powi(4) / 4.0,
+                p01 + dt * p11 + q * dt.powi(3) / 2.0,
+            ],
+            [
+                p10 + dt * p11 + q * dt.powi(3) / 2.0,
+                p11 + q * dt * dt,
+            ],
+        ];
+    }
+
+    fn update(&mut self, measurement: f64, scale: f64) {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let innovation_variance = p00 + (MEASUREMENT_NOISE_RATIO * scale).powi(2);
+        let (gain_value, gain_rate) = (p00 / innovation_variance, p10 / innovation_variance);
+        let innovation = measurement - self.value;
+        self.value += gain_value * innovation;
+        self.rate += gain_rate * innovation;
+        self.covariance = [
+            [(1.0 - gain_value) * p00, (1.0 - gain_value) * p01],
+            [p10 - gain_rate * p00, p11 - gain_rate * p01],
+        ];
+    }
+}
+
+struct Track {
+    id: u64,
+    class_name: String,
+    // Center x, center y, width, height
+    axes: [AxisFilter; 4],
+    started_at: i64,
+    age: u32,
+    hits: u32,
+    misses: u32,
+}
+
+impl Track {
+    fn new(id: u64, object: &DetectedObject, timestamp: i64) -> Self {
+        let bounding_box = &object.bounding_box;
+        let scale = box_scale(bounding_box);
+        let (center_x, center_y) = bounding_box.center();
+        Self {
+            id,
+            class_name: object.class_name.clone(),
+            axes: [center_x, center_y, bounding_box.width, bounding_box.height]
+                .map(|value| AxisFilter::new(value as f64, scale)),
+            started_at: timestamp,
+            age: 1,
+            hits: 1,
+            misses: 0,
+        }
+    }
+
+    fn predicted_box(&self) -> BoundingBox {
+        let [center_x, center_y, width, height] = self.axes.each_ref().map(|axis| axis.value as f32);
+        BoundingBox::from_center(center_x, center_y, width.max(0.0), height.max(0.0))
+    }
+
+    fn predict(&mut self, dt: f64) {
+        let scale = box_scale(&self.predicted_box());
+        for axis in &mut self.axes {
+            axis.predict(dt, scale);
+        }
+        self.age += 1;
+    }
+
+    fn update(&mut self, bounding_box: &BoundingBox) {
+        let scale = box_scale(bounding_box);
+        let (center_x, center_y) = bounding_box.center();
+        let measurements = [center_x, center_y, bounding_box.width, bounding_box.height];
+        for (axis, measurement) in self.axes.iter_mut().zip(measurements) {
+            axis.update(measurement as f64, scale);
+        }
+        self.hits += 1;
+        self.misses = 0;
+    }
+
+    fn info(&self, timestamp: i64) -> TrackInfo {
+        TrackInfo {
+            track_id: self.id,
+            age: self.age,
+            // Frames only move forward, so this can only saturate on extreme timestamps
+            age_ms: timestamp.saturating_sub(self.started_at),
+            hits: self.hits,
+            velocity_x: self.axes[0].rate as f32,
+            velocity_y: self.axes[1].rate as f32,
+        }
+    }
+}
+
+fn box_scale(bounding_box: &BoundingBox) -> f64 {
+    (bounding_box.width.max(bounding_box.height) as f64).max(f64::EPSILON)
+}
+
+struct StreamTracks {
+    tracks: Vec<Track>,
+    next_id: u64,
+    last_timestamp: Option<i64>,
+    last_active: Instant,
+}
+
+impl StreamTracks {
+    fn new() -> Self {
+        Self {
+            tracks: Vec::new(),
+            next_id: 1,
+            last_timestamp: None,
+            last_active: Instant::now(),
+        }
+    }
+
+    // Returns the ids of tracks retired by this frame
+    fn update(
+        &mut self,
+        objects: &mut [DetectedObject],
+        timestamp: i64,
+        params: &TrackingParams,
+    ) -> Result<Vec<u64>, ModelError> {
+        if let Some(last) = self.last_timestamp.filter(|last| timestamp <= *last) {
+            return Err(ModelError::InvalidInput(format!(
+                "frame timestamp {} is not after the stream's previous frame at {}",
+                timestamp, last
+            )));
+        }
+        let elapsed_ms = match self.last_timestamp {
+            Some(last) => timestamp.checked_sub(last).ok_or_else(|| {
+                ModelError::InvalidInput(format!(
+                    "frame timestamp {} is too far from the stream's previous frame at {}",
+                    timestamp, last
+                ))
+            })?,
+            None => 0,
+        };
+        let dt = elapsed_ms as f64 / 1000.0;
+        self.last_timestamp = Some(timestamp);
+        self.last_active = Instant::now();
+
+        for track in &mut self.tracks {
+            track.predict(dt);
+        }
+
+        // Hungarian assignment on 1 - IoU, objects only match tracks of their own class
+        let size = self.tracks.len().max(objects.len());
+        let mut cost = vec![vec![1.0; size]; size];
+        for (i, track) in self.tracks.iter().enumerate() {
+            let predicted = track.predicted_box();
+            for (j, object) in objects.iter().enumerate() {
+                // A non-finite box has a meaningless IoU, and a NaN cost would stall the matching
+                let iou = predicted.iou(&object.bounding_box) as f64;
+                if object.class_name == track.class_name && object.bounding_box.is_finite() && iou.is_finite() {
+                    cost[i][j] = 1.0 - iou;
+                }
+            }
+        }
+        let mut object_tracks = vec![None; objects.len()];
+        let mut matched_tracks = vec![false; self.tracks.len()];
+        for (i, j) in hungarian(&cost).into_iter().enumerate() {
+            if i < self.tracks.len() && j < objects.len() && 1.0 - cost[i][j] >= params.iou_threshold as f64 {
+                self.tracks[i].update(&objects[j].bounding_box);
+                object_tracks[j] = Some(self.tracks[i].id);
+                matched_tracks[i] = true;
+            }
+        }
+
+        for (track, matched) in self.tracks.iter_mut().zip(matched_tracks) {
+            if !matched {
+                track.misses += 1;
+            }
+        }
+        let mut retired = Vec::new();
+        self.tracks.retain(|track| {
+            let lost = track.misses > params.max_age;
+            if lost {
+                retired.push(track.id);
+            }
+            !lost
+        });
+
+        for (object, track_id) in objects.iter_mut().zip(object_tracks) {
+            let track_id = match track_id {
+                Some(track_id) => track_id,
+                // Left untracked rather than starting a track that could never be predicted
+                None if !object.bounding_box.is_finite() => continue,
+                None => {
+                    let track_id = self.next_id;
+                    self.next_id += 1;
+                    self.tracks.push(Track::new(track_id, object, timestamp));
+                    track_id
+                }
+            };
+            let track = self.tracks.iter().find(|track| track.id == track_id).expect("matched tracks are never retired");
+            object.id = format!("track_{}", track_id);
+            object.track = Some(track.info(timestamp));
+        }
+        Ok(retired)
+    }
+}
+
+// Minimum cost perfect matching on a square matrix, returns the column for each row
+fn hungarian(cost: &[Vec<f64>]) -> Vec<usize> {
+    let n = cost.len();
+    let mut row_potential = vec![0.0; n + 1];
+    let mut column_potential = vec![0.0; n + 1];
+    // Row matched to each column, 1-based with 0 for none
+    let mut column_row = vec![0usize; n + 1];
+    let mut way = vec![0usize; n + 1];
+
+    for row in 1..=n {
+        column_row[0] = row;
+        let mut column = 0;
+        let mut min_slack = vec![f64::INFINITY; n + 1];
+        let mut used = vec![false; n + 1];
+        loop {
+            used[column] = true;
+            let current_row = column_row[column];
+            let mut delta = f64::INFINITY;
+            let mut next_column = 0;
+            for j in 1..=n {
+                if used[j] {
+                    continue;
+                }
+                let slack = cost[current_row - 1][j - 1] - row_potential[current_row] - column_potential[j];
+                if slack < min_slack[j] {
+                    min_slack[j] = slack;
+                    way[j] = column;
+                }
+                if min_slack[j] < delta {
+                    delta = min_slack[j];
+                    next_column = j;
+                }
+            }
+            for j in 0..=n {
+                if used[j] {
+                    row_potential[column_row[j]] += delta;
+                    column_potential[j] -= delta;
+                } else {
+                    min_slack[j] -= delta;
+                }
+            }
+            column = next_column;
+            if column_row[column] == 0 {
+                break;
+            }
+        }
+        loop {
+            let previous = way[column];
+            column_row[column] = column_row[previous];
+            column = previous;
+            if column == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0; n];
+    for j in 1..=n {
+        if column_row[j] != 0 {
+            assignment[column_row[j] - 1] = j - 1;
+        }
+    }
+    assignment
+}
+
+// Tracks per stream, shared by every detector backend
+pub struct Tracker {
+    params: TrackingParams,
+    streams: DashMap<String, StreamTracks>,
+}
+
+impl Tracker {
+    pub fn new(params: TrackingParams) -> Result<Self, ModelError> {
+        params.validate()?;
+        Ok(Self {
+            params,
+            streams: DashMap::new(),
+        })
+    }
+
+    pub fn params(&self) -> &TrackingParams {
+        &self.params
+    }
+
+    // Replaces object ids with track ids and fills in their track info, returns the
+    // tracks this frame retired
+    pub fn track(&self, stream: &str, timestamp: i64, objects: &mut [DetectedObject]) -> Result<Vec<u64>, ModelError> {
+        if !self.streams.contains_key(stream) && self.streams.len() >= MAX_STREAMS {
+            self.streams.retain(|_, tracks| tracks.last_active.elapsed() < STREAM_IDLE_TIMEOUT);
+            if self.streams.len() >= MAX_STREAMS {
+                return Err(ModelError::InvalidInput(format!(
+                    "already tracking {} streams, cannot start {}",
+                    MAX_STREAMS, stream
+                )));
+            }
+        }
+        let mut tracks = self.streams.entry(stream.to_string()).or_insert_with(StreamTracks::new);
+        tracks.update(objects, timestamp, &self.params)
+    }
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self {
+            params: TrackingParams::default(),
+            streams: DashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(class_name: &str, x: f32, y: f32) -> DetectedObject {
+        DetectedObject {
+            id: String::new(),
+            class_name: class_name.to_string(),
+            confidence: 0.9,
+            bounding_box: BoundingBox { x, y, width: 10.0, height: 10.0 },
+            track: None,
+        }
+    }
+
+    #[test]
+    fn hungarian_finds_the_cheapest_assignment() {
+        // Greedy on the first row would take column 0 and force the 10 in row 1
+        let cost = vec![vec![1.0, 2.0, 3.0], vec![1.5, 10.0, 4.0], vec![3.0, 1.0, 2.0]];
+        assert_eq!(hungarian(&cost), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn hungarian_is_a_permutation_with_ties() {
+        let mut assignment = hungarian(&vec![vec![1.0; 4]; 4]);
+        assignment.sort_unstable();
+        assert_eq!(assignment, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn objects_keep_their_track_between_frames() {
+        let tracker = Tracker::default();
+        let mut first = vec![object("car", 0.0, 0.0), object("car", 100.0, 0.0)];
+        tracker.track("s", 0, &mut first).unwrap();
+        // Listed in the other order and moved a little
+        let mut second = vec![object("car", 101.0, 1.0), object("car", 1.0, 1.0)];
+        tracker.track("s", 100, &mut second).unwrap();
+        assert_eq!(second[0].id, first[1].id);
+        assert_eq!(second[1].id, first[0].id);
+    }
+
+    #[test]
+    fn non_finite_boxes_are_left_untracked() {
+        let tracker = Tracker::default();
+        let mut first = vec![object("car", 0.0, 0.0)];
+        tracker.track("s", 0, &mut first).unwrap();
+        let mut second = vec![object("car", f32::NAN, f32::NAN), object("car", 0.0, 0.0)];
+        tracker.track("s", 100, &mut second).unwrap();
+        assert_eq!(second[1].id, first[0].id);
+        assert!(second[0].track.is_none());
+    }
+
+    #[test]
+    fn timestamps_too_far_apart_are_rejected() {
+        let tracker = Tracker::default();
+        let mut first = vec![object("car", 0.0, 0.0)];
+        tracker.track("s", i64::MIN, &mut first).unwrap();
+        let mut second = vec![object("car", 0.0, 0.0)];
+        assert!(tracker.track("s", 1, &mut second).is_err());
+    }
+}
@@ -1,64 +1,742 @@
 use serde::{Deserialize, Serialize};
+use ndarray::{s, Array1, Array2};
+use rand::rngs::StdRng;
+use schemars::JsonSchema;
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::ModelError;
+use crate::ml::model::Model;
+
+const MODEL_VERSION: &str = "1.0.0";
+
+// Filter tuning. Positions are in scene units, time in seconds.
+const DEFAULT_PROCESS_NOISE: f64 = 4.0;
+const DEFAULT_MEASUREMENT_NOISE: f64 = 0.5;
+// Prior for an acceleration that two points cannot determine
+const INITIAL_DERIVATIVE_VARIANCE: f64 = 100.0;
+const MIN_DT_SECONDS: f64 = 1e-3;
+const MAX_PREDICTION_STEPS: usize = 1000;
+const MAX_OUTPUT_INTERVAL_MS: i64 = 3_600_000;
+const DEFAULT_PREDICTION_HORIZON: usize = 10;
+// Spread is judged against the distance the history covers, and never against less than
+// this many measurement sigmas so a stationary track is not penalised
+const MIN_CONFIDENCE_SCALE: f64 = 20.0;
+
+// Maneuver hypotheses: turn rate in rad/s, stopping modelled as exponential velocity decay
+const TURN_RATE: f64 = 0.35;
+const STOP_TIME_CONSTANT: f64 = 1.5;
+const TURN_RATE_SIGMA: f64 = 0.15;
+const LONGITUDINAL_ACCEL_SIGMA: f64 = 1.5;
+const MANEUVER_WINDOW: usize = 5;
+const MIN_HEADING_SPEED: f64 = 0.1;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct TrajectoryPoint {
     pub x: f32,
     pub y: f32,
     pub timestamp: i64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum MotionModel {
+    #[default]
+    ConstantVelocity,
+    ConstantAcceleration,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct TrajectoryPredictionInput {
     pub history: Vec<TrajectoryPoint>,
+    #[serde(default = "default_prediction_horizon")]
     pub prediction_horizon: usize,
+    // Takes precedence over the step count when set
+    #[serde(default)]
+    pub prediction_horizon_ms: Option<i64>,
+    // Spacing of predicted points, defaults to the median sampling interval of the history
+    #[serde(default)]
+    pub output_interval_ms: Option<i64>,
+    #[serde(default)]
+    pub motion_model: MotionModel,
+}
+
+fn default_prediction_horizon() -> usize {
+    DEFAULT_PREDICTION_HORIZON
+}
+
+// 1-sigma position uncertainty, orientation in radians from the x axis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UncertaintyEllipse {
+    pub semi_major: f32,
+    pub semi_minor: f32,
+    pub orientation: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PredictedPoint {
+    pub x: f32,
+    pub y: f32,
+    pub timestamp: i64,
+    pub std_x: f32,
+    pub std_y: f32,
+    pub uncertainty: UncertaintyEllipse,
+}
+
+// Turns follow the usual y-up convention: left is counter-clockwise
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Maneuver {
+    Straight,
+    LeftTurn,
+    RightTurn,
+    Stop,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrajectoryHypothesis {
+    pub maneuver: Maneuver,
+    pub probability: f32,
+    pub predictions: Vec<PredictedPoint>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TrajectoryPredictionOutput {
-    pub predictions: Vec<TrajectoryPoint>,
+    pub predictions: Vec<PredictedPoint>,
     pub confidence: f32,
+    // Ranked by probability, most likely first
+    pub hypotheses: Vec<TrajectoryHypothesis>,
+}
+
+impl MotionModel {
+    fn state_dim(self) -> usize {
+        match self {
+            MotionModel::ConstantVelocity => 4,
+            MotionModel::ConstantAcceleration => 6,
+        }
+    }
+
+    // State layout is [x, y, vx, vy] with [ax, ay] appended for constant acceleration
+    fn transition(self, dt: f64) -> Array2<f64> {
+        let mut f = Array2::eye(self.state_dim());
+        f[[0, 2]] = dt;
+        f[[1, 3]] = dt;
+        if self == MotionModel::ConstantAcceleration {
+            f[[0, 4]] = 0.5 * dt * dt;
+            f[[1, 5]] = 0.5 * dt * dt;
+            f[[2, 4]] = dt;
+            f[[3, 5]] = dt;
+        }
+        f
+    }
+
+    // Discretised white noise on the highest derivative, applied to each axis independently
+    fn process_noise(self, dt: f64, intensity: f64) -> Array2<f64> {
+        let block = match self {
+            MotionModel::ConstantVelocity => vec![
+                vec![dt.powi(3) / 3.0, dt.powi(2) / 2.0],
+                vec![dt.powi(2) / 2.0, dt],
+            ],
+            MotionModel::ConstantAcceleration => vec![
+                vec![dt.powi(5) / 20.0, dt.powi(4) / 8.0, dt.powi(3) / 6.0],
+                vec![dt.powi(4) / 8.0, dt.powi(3) / 3.0, dt.powi(2) / 2.0],
+                vec![dt.powi(3) / 6.0, dt.powi(2) / 2.0, dt],
+            ],
+        };
+
+        let mut q = Array2::zeros((self.state_dim(), self.state_dim()));
+        for axis in 0..2 {
+            for (i, row) in block.iter().enumerate() {
+                for (j, value) in row.iter().enumerate() {
+                    q[[axis + 2 * i, axis + 2 * j]] = value * intensity;
+                }
+            }
+        }
+        q
+    }
+}
+
+impl Maneuver {
+    const ALL: [Maneuver; 4] = [
+        Maneuver::Straight,
+        Maneuver::LeftTurn,
+        Maneuver::RightTurn,
+        Maneuver::Stop,
+    ];
+
+    fn prior(self) -> f64 {
+        match self {
+            Maneuver::Straight => 0.55,
+            Maneuver::LeftTurn | Maneuver::RightTurn | Maneuver::Stop => 0.15,
+        }
+    }
+
+    // Expected (turn rate, longitudinal acceleration) while performing the maneuver
+    fn signature(self, speed: f64) -> (f64, f64) {
+        match self {
+            Maneuver::Straight => (0.0, 0.0),
+            Maneuver::LeftTurn => (TURN_RATE, 0.0),
+            Maneuver::RightTurn => (-TURN_RATE, 0.0),
+            Maneuver::Stop => (0.0, -speed / STOP_TIME_CONSTANT),
+        }
+    }
+
+    // Linear in the state for a fixed maneuver, so covariance propagates exactly.
+    // Acceleration terms of the constant-acceleration model are held but not applied.
+    fn transition(self, model: MotionModel, dt: f64) -> Array2<f64> {
+        let (turn_rate, decay) = match self {
+            Maneuver::Straight => return model.transition(dt),
+            Maneuver::LeftTurn => (TURN_RATE, None),
+            Maneuver::RightTurn => (-TURN_RATE, None),
+            Maneuver::Stop => (0.0, Some(STOP_TIME_CONSTANT)),
+        };
+
+        let mut f = Array2::eye(model.state_dim());
+        if let Some(tau) = decay {
+            let retained = (-dt / tau).exp();
+            f[[0, 2]] = tau * (1.0 - retained);
+            f[[1, 3]] = tau * (1.0 - retained);
+            f[[2, 2]] = retained;
+            f[[3, 3]] = retained;
+        } else {
+            let (sin, cos) = (turn_rate * dt).sin_cos();
+            f[[0, 2]] = sin / turn_rate;
+            f[[0, 3]] = -(1.0 - cos) / turn_rate;
+            f[[1, 2]] = (1.0 - cos) / turn_rate;
+            f[[1, 3]] = sin / turn_rate;
+            f[[2, 2]] = cos;
+            f[[2, 3]] = -sin;
+            f[[3, 2]] = sin;
+            f[[3, 3]] = cos;
+        }
+        f
+    }
+}
+
+#[derive(Clone)]
+struct KalmanFilter {
+    model: MotionModel,
+    state: Array1<f64>,
+    covariance: Array2<f64>,
+    process_noise: f64,
+    measurement_variance: f64,
+}
+
+impl KalmanFilter {
+    // How many leading points seed the state rather than update it
+    fn initial_points(model: MotionModel, history_len: usize) -> usize {
+        (model.state_dim() / 2).min(history_len)
+    }
+
+    // Starts at the last of the initial points with the derivatives of the polynomial through
+    // them, so the state owes nothing to the units of the scene
+    fn new(model: MotionModel, initial: &[TrajectoryPoint], process_noise: f64, measurement_noise: f64) -> Self {
+        let n = model.state_dim();
+        let measurement_variance = measurement_noise * measurement_noise;
+        let times: Vec<f64> = initial.iter().map(|p| p.timestamp as f64 / 1000.0).collect();
+        let rows = Self::finite_differences(&times);
+
+        let mut state = Array1::zeros(n);
+        let mut covariance = Array2::eye(n) * INITIAL_DERIVATIVE_VARIANCE;
+        for axis in 0..2 {
+            let positions: Vec<f64> = initial
+                .iter()
+                .map(|p| if axis == 0 { p.x as f64 } else { p.y as f64 })
+                .collect();
+            for (i, row) in rows.iter().enumerate() {
+                state[axis + 2 * i] = row.iter().zip(&positions).map(|(c, p)| c * p).sum();
+                // Each position carries the measurement variance independently
+                for (j, other) in rows.iter().enumerate() {
+                    covariance[[axis + 2 * i, axis + 2 * j]] =
+                        measurement_variance * row.iter().zip(other).map(|(a, b)| a * b).sum::<f64>();
+                }
+            }
+        }
+
+        Self {
+            model,
+            state,
+            covariance,
+            process_noise,
+            measurement_variance,
+        }
+    }
+
+    // Coefficients taking positions at `times` to position, velocity and, given three
+    // points, acceleration at the last time
+    fn finite_differences(times: &[f64]) -> Vec<Vec<f64>> {
+        match *times {
+            [t0, t1] => {
+                let d = t1 - t0;
+                vec![vec![0.0, 1.0], vec![-1.0 / d, 1.0 / d]]
+            }
+            [t0, t1, t2] => {
+                let (d1, d2) = (t1 - t0, t2 - t1);
+                let accel = [2.0 / (d1 * (d1 + d2)), -2.0 / (d1 * d2), 2.0 / (d2 * (d1 + d2))];
+                let velocity = [
+                    accel[0] * d2 / 2.0,
+                    -1.0 / d2 + accel[1] * d2 / 2.0,
+                    1.0 / d2 + accel[2] * d2 / 2.0,
+                ];
+                vec![vec![0.0, 0.0, 1.0], velocity.to_vec(), accel.to_vec()]
+            }
+            _ => unreachable!("the filter starts from two or three points"),
+        }
+    }
+
+    fn predict(&mut self, dt: f64) {
+        self.predict_with(self.model.transition(dt), dt);
+    }
+
+    fn predict_with(&mut self, f: Array2<f64>, dt: f64) {
+        self.state = f.dot(&self.state);
+        self.covariance = f.dot(&self.covariance).dot(&f.t()) + self.model.process_noise(dt, self.process_noise);
+    }
+
+    // Position-only measurement update, returns the normalised innovation squared
+    fn update(&mut self, x: f64, y: f64) -> f64 {
+        let innovation = [x - self.state[0], y - self.state[1]];
+
+        let s00 = self.covariance[[0, 0]] + self.measurement_variance;
+        let s01 = self.covariance[[0, 1]];
+        let s11 = self.covariance[[1, 1]] + self.measurement_variance;
+        let det = s00 * s11 - s01 * s01;
+        let s_inv = [[s11 / det, -s01 / det], [-s01 / det, s00 / det]];
+
+        let n = self.model.state_dim();
+        let mut gain = Array2::zeros((n, 2));
+        for i in 0..n {
+            let p0 = self.covariance[[i, 0]];
+            let p1 = self.covariance[[i, 1]];
+            gain[[i, 0]] = p0 * s_inv[0][0] + p1 * s_inv[1][0];
+            gain[[i, 1]] = p0 * s_inv[0][1] + p1 * s_inv[1][1];
+        }
+
+        for i in 0..n {
+            self.state[i] += gain[[i, 0]] * innovation[0] + gain[[i, 1]] * innovation[1];
+        }
+
+        // P = (I - KH) P, then re-symmetrise to keep rounding error from accumulating
+        let observed_rows = self.covariance.slice(s![0..2, ..]).to_owned();
+        self.covariance = &self.covariance - &gain.dot(&observed_rows);
+        self.covariance = (&self.covariance + &self.covariance.t()) * 0.5;
+
+        innovation[0] * (s_inv[0][0] * innovation[0] + s_inv[0][1] * innovation[1])
+            + innovation[1] * (s_inv[1][0] * innovation[0] + s_inv[1][1] * innovation[1])
+    }
+
+    fn velocity(&self) -> (f64, f64) {
+        (self.state[2], self.state[3])
+    }
+
+    fn predicted_point(&self, timestamp: i64) -> PredictedPoint {
+        PredictedPoint {
+            x: self.state[0] as f32,
+            y: self.state[1] as f32,
+            timestamp,
+            std_x: self.covariance[[0, 0]].max(0.0).sqrt() as f32,
+            std_y: self.covariance[[1, 1]].max(0.0).sqrt() as f32,
+            uncertainty: self.uncertainty(),
+        }
+    }
+
+    fn uncertainty(&self) -> UncertaintyEllipse {
+        let a = self.covariance[[0, 0]];
+        let b = self.covariance[[0, 1]];
+        let c = self.covariance[[1, 1]];
+
+        let mean = (a + c) / 2.0;
+        let radius = (((a - c) / 2.0).powi(2) + b * b).sqrt();
+
+        UncertaintyEllipse {
+            semi_major: (mean + radius).max(0.0).sqrt() as f32,
+            semi_minor: (mean - radius).max(0.0).sqrt() as f32,
+            orientation: (0.5 * (2.0 * b).atan2(a - c)) as f32,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrajectoryParams {
+    pub process_noise: f64,
+    pub measurement_noise: f64,
+}
+
+impl Default for TrajectoryParams {
+    fn default() -> Self {
+        Self {
+            process_noise: DEFAULT_PROCESS_NOISE,
+            measurement_noise: DEFAULT_MEASUREMENT_NOISE,
+        }
+    }
 }
 
 pub struct TrajectoryPredictor {
-    // For now, we'll use a simple linear predictor
+    // Kalman filter run over the full history, then propagated without measurements
     // In production, this would be a proper LSTM or Transformer model
+    process_noise: f64,
+    measurement_noise: f64,
 }
 
 impl TrajectoryPredictor {
     pub fn new() -> anyhow::Result<Self> {
-        Ok(Self {})
+        Ok(Self::with_params(TrajectoryParams::default())?)
+    }
+
+    pub fn with_params(params: TrajectoryParams) -> Result<Self, ModelError> {
+        let valid = |noise: f64| noise.is_finite() && noise > 0.0;
+        if !valid(params.process_noise) || !valid(params.measurement_noise) {
+            return Err(ModelError::InvalidInput(
+                "process_noise and measurement_noise must be positive".to_string(),
+            ));
+        }
+        Ok(Self {
+            process_noise: params.process_noise,
+            measurement_noise: params.measurement_noise,
+        })
     }
 
     pub fn predict(&self, input: &TrajectoryPredictionInput) -> anyhow::Result<TrajectoryPredictionOutput> {
-        // Simple linear extrapolation for now
-        // In production, this would use a trained neural network
-        
-        if input.history.len() < 2 {
-            return Ok(TrajectoryPredictionOutput {
-                predictions: vec![],
-                confidence: 0.0,
-            });
+        Self::validate(input)?;
+
+        let initial = KalmanFilter::initial_points(input.motion_model, input.history.len());
+        let mut filter = KalmanFilter::new(
+            input.motion_model,
+            &input.history[..initial],
+            self.process_noise,
+            self.measurement_noise,
+        );
+
+        // Each measurement is propagated by its own interval, so irregular sampling is handled.
+        // Only these updates test the filter, the initial points fit it exactly.
+        let mut nis_sum = 0.0;
+        let mut velocities = vec![(input.history[initial - 1].timestamp, filter.velocity())];
+        for pair in input.history[initial - 1..].windows(2) {
+            let dt = (pair[1].timestamp - pair[0].timestamp) as f64 / 1000.0;
+            filter.predict(dt);
+            nis_sum += filter.update(pair[1].x as f64, pair[1].y as f64);
+            velocities.push((pair[1].timestamp, filter.velocity()));
         }
+        let updates = input.history.len() - initial;
+        let mean_nis = (updates > 0).then(|| nis_sum / updates as f64);
+        let path_length: f64 = input
+            .history
+            .windows(2)
+            .map(|pair| ((pair[1].x - pair[0].x) as f64).hypot((pair[1].y - pair[0].y) as f64))
+            .sum();
+        let scale = path_length.max(MIN_CONFIDENCE_SCALE * self.measurement_noise);
 
         let last = &input.history[input.history.len() - 1];
-        let prev = &input.history[input.history.len() - 2];
-        
-        let dx = last.x - prev.x;
-        let dy = last.y - prev.y;
-        let dt = (last.timestamp - prev.timestamp) as f32;
-        
-        let mut predictions = Vec::new();
-        for i in 1..=input.prediction_horizon {
-            predictions.push(TrajectoryPoint {
-                x: last.x + dx * i as f32,
-                y: last.y + dy * i as f32,
-                timestamp: last.timestamp + (dt * i as f32) as i64,
-            });
-        }
-        
+        let step_ms = input
+            .output_interval_ms
+            .unwrap_or_else(|| Self::median_interval_ms(&input.history));
+        let step_dt = step_ms as f64 / 1000.0;
+        let steps = Self::prediction_steps(input, step_ms)?;
+        let timestamps = (1..=steps as i64)
+            .map(|i| step_ms.checked_mul(i).and_then(|offset| last.timestamp.checked_add(offset)))
+            .collect::<Option<Vec<i64>>>()
+            .ok_or_else(|| ModelError::InvalidInput("prediction timestamps overflow an i64".to_string()))?;
+
+        let hypotheses = Self::maneuver_probabilities(&velocities)
+            .into_iter()
+            .map(|(maneuver, probability)| {
+                let mut branch = filter.clone();
+                let predictions = timestamps
+                    .iter()
+                    .map(|&timestamp| {
+                        branch.predict_with(maneuver.transition(input.motion_model, step_dt), step_dt);
+                        branch.predicted_point(timestamp)
+                    })
+                    .collect();
+                TrajectoryHypothesis {
+                    maneuver,
+                    probability: probability as f32,
+                    predictions,
+                }
+            })
+            .collect();
+
+        let mut predictions = Vec::with_capacity(steps);
+        for &timestamp in &timestamps {
+            filter.predict(step_dt);
+            predictions.push(filter.predicted_point(timestamp));
+        }
+
         Ok(TrajectoryPredictionOutput {
+            confidence: Self::confidence(mean_nis, predictions.last(), scale),
             predictions,
-            confidence: 0.85 + rand::random::<f32>() * 0.1,
+            hypotheses,
         })
     }
-}
\ No newline at end of file
+
+    fn validate(input: &TrajectoryPredictionInput) -> Result<(), ModelError> {
+        if input.history.len() < 2 {
+            return Err(ModelError::InvalidInput(format!(
+                "trajectory history needs at least 2 points, got {}",
+                input.history.len()
+            )));
+        }
+
+        for (i, pair) in input.history.windows(2).enumerate() {
+            if pair[1].timestamp <= pair[0].timestamp {
+                let problem = if pair[1].timestamp == pair[0].timestamp { "duplicate" } else { "out of order" };
+                return Err(ModelError::InvalidInput(format!(
+                    "history timestamp {} at index {} is {} (previous is {})",
+                    pair[1].timestamp,
+                    i + 1,
+                    problem,
+                    pair[0].timestamp
+                )));
+            }
+        }
+
+        // Timestamps only increase, so a span that fits means every interval fits too
+        let (first, last) = (&input.history[0], &input.history[input.history.len() - 1]);
+        if last.timestamp.checked_sub(first.timestamp).is_none() {
+            return Err(ModelError::InvalidInput(format!(
+                "history from {} to {} spans more milliseconds than an i64 holds",
+                first.timestamp, last.timestamp
+            )));
+        }
+
+        if let Some(interval) = input.output_interval_ms.filter(|ms| !(1..=MAX_OUTPUT_INTERVAL_MS).contains(ms)) {
+            return Err(ModelError::InvalidInput(format!(
+                "output_interval_ms must be between 1 and {}, got {}",
+                MAX_OUTPUT_INTERVAL_MS, interval
+            )));
+        }
+        if let Some(horizon) = input.prediction_horizon_ms.filter(|ms| *ms <= 0) {
+            return Err(ModelError::InvalidInput(format!(
+                "prediction_horizon_ms must be positive, got {}",
+                horizon
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn median_interval_ms(history: &[TrajectoryPoint]) -> i64 {
+        let mut intervals: Vec<i64> = history
+            .windows(2)
+            .map(|pair| pair[1].timestamp - pair[0].timestamp)
+            .collect();
+        intervals.sort_unstable();
+        intervals[intervals.len() / 2]
+    }
+
+    fn prediction_steps(input: &TrajectoryPredictionInput, step_ms: i64) -> Result<usize, ModelError> {
+        let steps = match input.prediction_horizon_ms {
+            Some(horizon_ms) => (horizon_ms / step_ms) as usize,
+            None => input.prediction_horizon,
+        };
+
+        if steps > MAX_PREDICTION_STEPS {
+            return Err(ModelError::InvalidInput(format!(
+                "prediction horizon of {} steps exceeds the limit of {}",
+                steps, MAX_PREDICTION_STEPS
+            )));
+        }
+        Ok(steps)
+    }
+
+    // Scores each maneuver against the turn rate and acceleration seen in the most
+    // recent filtered velocities, returning normalised probabilities in ranked order
+    fn maneuver_probabilities(velocities: &[(i64, (f64, f64))]) -> Vec<(Maneuver, f64)> {
+        let window = &velocities[velocities.len().saturating_sub(MANEUVER_WINDOW)..];
+        let (first_ts, (fvx, fvy)) = window[0];
+        let (last_ts, (lvx, lvy)) = window[window.len() - 1];
+        let elapsed = ((last_ts - first_ts) as f64 / 1000.0).max(MIN_DT_SECONDS);
+
+        let first_speed = fvx.hypot(fvy);
+        let speed = lvx.hypot(lvy);
+        let turn_rate = if window.len() > 1 && first_speed > MIN_HEADING_SPEED && speed > MIN_HEADING_SPEED {
+            let turned = lvy.atan2(lvx) - fvy.atan2(fvx);
+            turned.sin().atan2(turned.cos()) / elapsed
+        } else {
+            0.0
+        };
+        let accel = if window.len() > 1 { (speed - first_speed) / elapsed } else { 0.0 };
+
+        let log_scores: Vec<(Maneuver, f64)> = Maneuver::ALL
+            .iter()
+            .map(|&maneuver| {
+                let (expected_turn, expected_accel) = maneuver.signature(speed);
+                let turn_z = (turn_rate - expected_turn) / TURN_RATE_SIGMA;
+                let accel_z = (accel - expected_accel) / LONGITUDINAL_ACCEL_SIGMA;
+                (maneuver, maneuver.prior().ln() - 0.5 * (turn_z * turn_z + accel_z * accel_z))
+            })
+            .collect();
+
+        // Log-sum-exp keeps the normalisation stable for very unlikely maneuvers
+        let max_score = log_scores.iter().map(|(_, s)| *s).fold(f64::NEG_INFINITY, f64::max);
+        let total: f64 = log_scores.iter().map(|(_, s)| (s - max_score).exp()).sum();
+        let mut probabilities: Vec<(Maneuver, f64)> = log_scores
+            .into_iter()
+            .map(|(maneuver, s)| (maneuver, (s - max_score).exp() / total))
+            .collect();
+        probabilities.sort_by(|a, b| b.1.total_cmp(&a.1));
+        probabilities
+    }
+
+    // Combines how well the filter explained the history (NIS has expectation 2 for
+    // a 2D measurement) with how far the uncertainty has spread by the horizon, relative
+    // to the scale of the track. A history with nothing to test the filter against is
+    // judged on the spread alone.
+    fn confidence(mean_nis: Option<f64>, horizon_end: Option<&PredictedPoint>, scale: f64) -> f32 {
+        let consistency = mean_nis.map_or(1.0, |nis| (-(nis - 2.0).max(0.0) / 4.0).exp());
+        let spread = horizon_end
+            .map(|p| {
+                let sigma = ((p.uncertainty.semi_major.powi(2) + p.uncertainty.semi_minor.powi(2)) / 2.0).sqrt() as f64;
+                1.0 / (1.0 + sigma / scale)
+            })
+            .unwrap_or(1.0);
+
+        (consistency * spread).clamp(0.0, 1.0) as f32
+    }
+}
+
+impl Model for TrajectoryPredictor {
+    type Input = TrajectoryPredictionInput;
+    type Output = TrajectoryPredictionOutput;
+    type Params = TrajectoryParams;
+
+    fn name(&self) -> &'static str {
+        "kalman_filter"
+    }
+
+    fn version(&self) -> String {
+        MODEL_VERSION.to_string()
+    }
+
+    fn params(&self) -> Self::Params {
+        TrajectoryParams {
+            process_noise: self.process_noise,
+            measurement_noise: self.measurement_noise,
+        }
+    }
+
+    fn predict(&self, input: &Self::Input, _rng: &mut StdRng) -> anyhow::Result<Self::Output> {
+        TrajectoryPredictor::predict(self, input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 units/s along x and 5 along y, sampled every 100 ms
+    fn straight_track(points: i64) -> Vec<TrajectoryPoint> {
+        (0..points)
+            .map(|i| TrajectoryPoint { x: i as f32, y: 0.5 * i as f32, timestamp: i * 100 })
+            .collect()
+    }
+
+    fn input(history: Vec<TrajectoryPoint>) -> TrajectoryPredictionInput {
+        TrajectoryPredictionInput {
+            history,
+            prediction_horizon: DEFAULT_PREDICTION_HORIZON,
+            prediction_horizon_ms: None,
+            output_interval_ms: None,
+            motion_model: MotionModel::ConstantVelocity,
+        }
+    }
+
+    #[test]
+    fn constant_velocity_track_predicts_a_straight_line() {
+        let output = TrajectoryPredictor::new().unwrap().predict(&input(straight_track(20))).unwrap();
+        assert_eq!(output.predictions.len(), DEFAULT_PREDICTION_HORIZON);
+        for (step, point) in output.predictions.iter().enumerate() {
+            let i = 20 + step as i64;
+            assert_eq!(point.timestamp, i * 100);
+            assert!((point.x - i as f32).abs() < 0.1, "step {}: x {}", step, point.x);
+            assert!((point.y - 0.5 * i as f32).abs() < 0.1, "step {}: y {}", step, point.y);
+        }
+    }
+
+    #[test]
+    fn constant_acceleration_model_also_follows_a_straight_track() {
+        let mut request = input(straight_track(20));
+        request.motion_model = MotionModel::ConstantAcceleration;
+        let output = TrajectoryPredictor::new().unwrap().predict(&request).unwrap();
+        let last = output.predictions.last().unwrap();
+        assert!((last.x - 29.0).abs() < 0.5 && (last.y - 14.5).abs() < 0.5, "{:?}", last);
+    }
+
+    #[test]
+    fn uncertainty_grows_with_the_horizon() {
+        let output = TrajectoryPredictor::new().unwrap().predict(&input(straight_track(20))).unwrap();
+        for pair in output.predictions.windows(2) {
+            assert!(pair[1].std_x > pair[0].std_x && pair[1].std_y > pair[0].std_y);
+        }
+    }
+
+    // The same straight track scaled up, as if measured in other units
+    fn scaled_track(points: i64, scale: f32) -> Vec<TrajectoryPoint> {
+        straight_track(points)
+            .into_iter()
+            .map(|p| TrajectoryPoint { x: p.x * scale, y: p.y * scale, timestamp: p.timestamp })
+            .collect()
+    }
+
+    #[test]
+    fn fast_tracks_are_as_confident_as_slow_ones() {
+        let predictor = TrajectoryPredictor::new().unwrap();
+        for points in [5, 20] {
+            let slow = predictor.predict(&input(straight_track(points))).unwrap();
+            let fast = predictor.predict(&input(scaled_track(points, 20.0))).unwrap();
+            assert!(slow.confidence > 0.8, "{} points: slow {}", points, slow.confidence);
+            assert!(fast.confidence >= slow.confidence, "{} points: fast {} slow {}", points, fast.confidence, slow.confidence);
+            let last = fast.predictions.last().unwrap();
+            let i = (points + DEFAULT_PREDICTION_HORIZON as i64 - 1) as f32;
+            assert!((last.x - 20.0 * i).abs() < 0.5 && (last.y - 10.0 * i).abs() < 0.5, "{:?}", last);
+        }
+    }
+
+    #[test]
+    fn two_points_extrapolate_at_their_velocity() {
+        for scale in [1.0, 20.0] {
+            let output = TrajectoryPredictor::new().unwrap().predict(&input(scaled_track(2, scale))).unwrap();
+            for (step, point) in output.predictions.iter().enumerate() {
+                let i = 2.0 + step as f32;
+                assert!((point.x - scale * i).abs() < 1e-3 * scale, "step {}: x {}", step, point.x);
+                assert!((point.y - 0.5 * scale * i).abs() < 1e-3 * scale, "step {}: y {}", step, point.y);
+            }
+            assert!(output.confidence > 0.5, "scale {}: {}", scale, output.confidence);
+        }
+    }
+
+    #[test]
+    fn three_points_fit_constant_acceleration_exactly() {
+        // x = t^2 with t in tenths of a second, sampled unevenly
+        let history = [0i64, 100, 300]
+            .iter()
+            .map(|&ts| TrajectoryPoint { x: (ts * ts) as f32 / 1e4, y: 0.0, timestamp: ts })
+            .collect();
+        let mut request = input(history);
+        request.motion_model = MotionModel::ConstantAcceleration;
+        request.output_interval_ms = Some(100);
+        let output = TrajectoryPredictor::new().unwrap().predict(&request).unwrap();
+        for point in &output.predictions {
+            let t = point.timestamp as f32 / 100.0;
+            assert!((point.x - t * t).abs() < 1e-2, "t {}: x {}", t, point.x);
+        }
+    }
+
+    #[test]
+    fn straight_track_ranks_going_straight_first() {
+        let output = TrajectoryPredictor::new().unwrap().predict(&input(straight_track(20))).unwrap();
+        assert_eq!(output.hypotheses[0].maneuver, Maneuver::Straight);
+        let total: f32 = output.hypotheses.iter().map(|h| h.probability).sum();
+        assert!((total - 1.0).abs() < 1e-4, "{}", total);
+    }
+
+    #[test]
+    fn prediction_timestamps_that_overflow_are_rejected() {
+        let mut request = input(vec![
+            TrajectoryPoint { x: 0.0, y: 0.0, timestamp: i64::MAX - 200 },
+            TrajectoryPoint { x: 1.0, y: 0.0, timestamp: i64::MAX - 100 },
+        ]);
+        request.output_interval_ms = Some(1000);
+        let error = TrajectoryPredictor::new().unwrap().predict(&request).unwrap_err();
+        assert!(error.to_string().contains("overflow"), "{}", error);
+    }
+}
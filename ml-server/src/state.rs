@@ -1,24 +1,29 @@
 use std::sync::Arc;
+use axum::extract::ws::Message;
 use dashmap::DashMap;
-use tokio::sync::RwLock;
 
-use crate::ml::engine::MLEngine;
-use crate::models::ModelType;
+use crate::ml::{
+    engine::{EngineOptions, MLEngine},
+    registry::ModelVersions,
+};
 
 pub struct AppState {
     pub ml_engine: Arc<MLEngine>,
-    pub active_connections: DashMap<String, tokio::sync::mpsc::Sender<String>>,
-    pub model_versions: RwLock<std::collections::HashMap<ModelType, String>>,
+    // Outbound queue of every open WebSocket, keyed by connection id
+    pub active_connections: DashMap<String, tokio::sync::mpsc::Sender<Message>>,
+    // Every version each model has served, written by the engine as models are swapped
+    pub model_versions: ModelVersions,
 }
 
 impl AppState {
-    pub async fn new() -> Self {
-        let ml_engine = Arc::new(MLEngine::new().await);
-        
+    pub async fn new(options: EngineOptions) -> Self {
+        let model_versions = ModelVersions::default();
+        let ml_engine = Arc::new(MLEngine::new(options, model_versions.clone()).await);
+
         Self {
             ml_engine,
             active_connections: DashMap::new(),
-            model_versions: RwLock::new(std::collections::HashMap::new()),
+            model_versions,
         }
     }
 }
\ No newline at end of file
@@ -48,6 +48,8 @@ function startPrediction() {
         if (timestamp - lastUpdate >= updateInterval) {
             // Update predictions for all active agents
             state.activeAgents.forEach(async agent => {
+                if (agent.history.length < 2) return
+
                 try {
                     const response = await mlWebSocket.predictTrajectory(agent.history)
                     const prediction = response.prediction.predictions
@@ -150,7 +152,8 @@ function startMonitoring() {
             
             mlWebSocket.detectAnomaly(sensorReadings).then(response => {
                 const anomalyData = response.prediction
-                const threshold = parseFloat(document.getElementById('anomaly-threshold').value)
+                // The threshold the server applied to the charted sensor
+                const threshold = anomalyData.sensor_thresholds[0][1]
                 
                 if (charts && charts.anomaly) {
                     charts.anomaly.addDataPoint(
@@ -170,6 +173,18 @@ function startMonitoring() {
     requestAnimationFrame(updateMonitoring)
 }
 
+// The slider follows the server's score scale, which changes when a forest is trained
+function applyThresholdConfig(config) {
+    // Bounds arrive as f32, rounded so they sit on the step grid
+    const round = value => Math.round(value * 100) / 100
+    const slider = document.getElementById('anomaly-threshold')
+    slider.min = round(config.min)
+    slider.max = round(config.max)
+    slider.step = config.max - config.min > 1 ? 0.1 : 0.01
+    slider.value = round(config.thresholds.global)
+    document.getElementById('threshold-value').textContent = slider.value
+}
+
 function stopMonitoring() {
     state.isMonitoring = false
     document.getElementById('btn-start-monitoring').textContent = 'Start Monitoring'
@@ -323,6 +338,16 @@ function setupEventListeners() {
     document.getElementById('anomaly-threshold').addEventListener('input', e => {
         document.getElementById('threshold-value').textContent = e.target.value
     })
+    // Sent once the slider is released, the server's thresholds apply to every client
+    document.getElementById('anomaly-threshold').addEventListener('change', e => {
+        mlWebSocket.setAnomalyThreshold(parseFloat(e.target.value))
+    })
+    mlWebSocket.subscribe('anomaly_threshold', applyThresholdConfig)
+    mlWebSocket.subscribe('model_update', update => {
+        // The dashboard drives the default anomaly model, other instances change independently
+        if (update.model === 'anomaly_detection') mlWebSocket.requestAnomalyThreshold()
+    })
+    mlWebSocket.requestAnomalyThreshold()
 
     document.getElementById('btn-start-detection').addEventListener('click', startDetection)
     document.getElementById('btn-simulate-scene').addEventListener('click', simulateScene)
@@ -51,11 +51,28 @@ export class MLWebSocketService {
                 case 'inference_response':
                     this.handleInferenceResponse(message.payload)
                     break
+                case 'batch_inference_response': {
+                    const handler = this.takePendingRequest(message.payload.request_id)
+                    if (handler) handler.resolve(message.payload)
+                    break
+                }
                 case 'heartbeat':
                     // Keep connection alive
                     break
+                case 'model_update':
+                    this.notifyHandlers('model_update', message.payload)
+                    break
+                case 'subscription_update':
+                    this.notifyHandlers(`stream:${message.payload.subscription_id}`, message.payload)
+                    break
+                case 'anomaly_threshold':
+                    this.notifyHandlers('anomaly_threshold', message.payload)
+                    break
+                case 'subscribed':
+                case 'unsubscribed':
+                    break
                 case 'error':
-                    console.error('Server error:', message.payload)
+                    this.handleError(message.payload)
                     break
                 default:
                     console.log('Unknown message type:', message.message_type)
@@ -65,22 +82,47 @@ export class MLWebSocketService {
         }
     }
 
-    handleInferenceResponse(response) {
-        // Find the first matching request for this model type
-        let handled = false
-        for (const [requestId, handler] of this.requestQueue.entries()) {
-            if (handler.modelType === response.model_type) {
-                handler.resolve(response)
-                this.requestQueue.delete(requestId)
-                handled = true
-                break
+    // Responses echo the request_id they were sent with, older servers only give the model type
+    takePendingRequest(requestId, modelType) {
+        if (requestId && this.requestQueue.has(requestId)) {
+            const handler = this.requestQueue.get(requestId)
+            this.requestQueue.delete(requestId)
+            return handler
+        }
+        if (requestId) return null
+
+        for (const [id, handler] of this.requestQueue.entries()) {
+            if (modelType && handler.modelType === modelType) {
+                this.requestQueue.delete(id)
+                return handler
             }
         }
+        return null
+    }
+
+    handleInferenceResponse(response) {
+        const handler = this.takePendingRequest(response.request_id, response.model_type)
+        if (handler) {
+            handler.resolve(response)
+        }
         
         // Also notify any registered handlers
-        const modelHandlers = this.messageHandlers.get(response.model_type)
-        if (modelHandlers) {
-            modelHandlers.forEach(handler => handler(response))
+        this.notifyHandlers(response.model_type, response)
+    }
+
+    handleError(error) {
+        console.error('Server error:', error)
+
+        const handler = this.takePendingRequest(error.request_id, error.model_type)
+        if (handler) {
+            handler.reject(new Error(`${error.code}: ${error.message}`))
+        }
+    }
+
+    notifyHandlers(key, payload) {
+        const handlers = this.messageHandlers.get(key)
+        if (handlers) {
+            handlers.forEach(handler => handler(payload))
         }
     }
 
@@ -131,13 +173,15 @@ export class MLWebSocketService {
         return this.sendRequest(request)
     }
 
-    async detectAnomaly(sensorReadings) {
+    // threshold, when given, replaces the server's thresholds for this request only
+    async detectAnomaly(sensorReadings, threshold) {
         const request = {
             message_type: 'inference_request',
             payload: {
                 model_type: 'anomaly_detection',
                 data: {
-                    sensor_readings: sensorReadings
+                    sensor_readings: sensorReadings,
+                    threshold
                 }
             }
         }
@@ -145,6 +189,20 @@ export class MLWebSocketService {
         return this.sendRequest(request)
     }
 
+    // The reply arrives as an 'anomaly_threshold' message, subscribe to receive it
+    requestAnomalyThreshold() {
+        this.send({ message_type: 'get_anomaly_threshold', payload: {} })
+    }
+
+    // Omit sensorType to set the global threshold. The new configuration comes back
+    // through the 'model_update' broadcast.
+    setAnomalyThreshold(threshold, sensorType) {
+        this.send({
+            message_type: 'set_anomaly_threshold',
+            payload: { threshold, sensor_type: sensorType }
+        })
+    }
+
     async detectObjects(frameId, simulateComplex = false) {
         const request = {
             message_type: 'inference_request',
@@ -176,9 +234,24 @@ export class MLWebSocketService {
         return this.sendRequest(request)
     }
 
+    // items: [{ model_type, data, seed }], model_type may be omitted when modelType is given.
+    // Resolves with per-item results, failed items carry an error instead of a prediction.
+    async inferBatch(items, modelType) {
+        const request = {
+            message_type: 'batch_inference_request',
+            payload: {
+                model_type: modelType,
+                items
+            }
+        }
+
+        return this.sendRequest(request)
+    }
+
     sendRequest(request) {
         return new Promise((resolve, reject) => {
-            const requestId = `${request.payload.model_type}_${Date.now()}_${Math.random()}`
+            const requestId = `${request.payload.model_type || 'batch'}_${Date.now()}_${Math.random()}`
+            request.payload.request_id = requestId
             
             this.requestQueue.set(requestId, { resolve, reject, modelType: request.payload.model_type })
             
@@ -210,6 +283,31 @@ export class MLWebSocketService {
         }
     }
 
+    // Ask the server to push results. Pass `data` to have it run the model at `rateHz`,
+    // omit it to receive every result the model produces (optionally anomalies only).
+    // Anomaly models learn from every input, so the server only accepts the latter for them.
+    streamModel(subscriptionId, modelType, handler, { data, rateHz, alertsOnly = false } = {}) {
+        const unsubscribe = this.subscribe(`stream:${subscriptionId}`, handler)
+        this.send({
+            message_type: 'subscribe',
+            payload: {
+                subscription_id: subscriptionId,
+                model_type: modelType,
+                data,
+                rate_hz: rateHz,
+                alerts_only: alertsOnly
+            }
+        })
+
+        return () => {
+            unsubscribe()
+            this.send({
+                message_type: 'unsubscribe',
+                payload: { subscription_id: subscriptionId }
+            })
+        }
+    }
+
     disconnect() {
         if (this.ws) {
             this.ws.close()